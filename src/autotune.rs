@@ -0,0 +1,284 @@
+// Relay autotuning, after Astrom and Hagglund.
+// Switching the output between two levels around a set point makes most loops oscillate
+// at the frequency where they are half a turn behind, the same place a P controller would.
+// The size and period of that oscillation give the ultimate gain and period,
+// which the classic rules turn into PID gains without ever running the loop near instability.
+
+use core::f32::consts;
+use micromath::F32;
+
+use crate::pid::PIDGains;
+
+// How the ultimate gain and period become gains, from the most aggressive to the gentlest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TuningRule {
+    // quarter decay, fast but overshoots a lot.
+    ZieglerNichols,
+    // less gain and much less integral, made for loops that should not ring.
+    TyreusLuyben,
+    // Ziegler Nichols with a fraction of the gain, little to no overshoot.
+    NoOvershoot,
+}
+
+impl TuningRule {
+    pub fn gains(&self, result: &RelayResult) -> PIDGains {
+        let ku = result.ultimate_gain;
+        let tu = result.ultimate_period;
+        // gain, integral time and derivative time.
+        let (kp, ti, td) = match self {
+            TuningRule::ZieglerNichols => (0.6 * ku, 0.5 * tu, 0.125 * tu),
+            TuningRule::TyreusLuyben => (ku / 2.2, 2.2 * tu, tu / 6.3),
+            TuningRule::NoOvershoot => (0.2 * ku, 0.5 * tu, tu / 3.0),
+        };
+        PIDGains {
+            kp,
+            ki: kp / ti,
+            kd: kp * td,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayConfig {
+    // the output is this much either side of 0.
+    pub amplitude: f32,
+    // the measurement has to be this far past the set point before the output switches,
+    // keep it above the noise or the relay chatters.
+    pub hysteresis: f32,
+    // give up if the measurement gets further than this from the set point.
+    pub max_deviation: f32,
+    // give up if it takes longer than this, seconds.
+    pub max_duration: f32,
+    // cycles measured and averaged, after the first few are let go to settle.
+    pub cycles: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RelayResult {
+    // output per unit of measurement.
+    pub ultimate_gain: f32,
+    // seconds.
+    pub ultimate_period: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutotuneError {
+    // nothing to measure the loop with.
+    NoRotorTracker,
+    // the measurement went past the max deviation, the amplitude is too big for this loop.
+    DeviationLimit,
+    // not enough cycles within the max duration, the amplitude is too small to get past friction.
+    Timeout,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayState {
+    // keep going with this output.
+    Running(f32),
+    Done(RelayResult),
+    Failed(AutotuneError),
+}
+
+// The relay on its own, fed a measurement each loop and giving back the output.
+pub struct RelayAutotune {
+    config: RelayConfig,
+    setpoint: f32,
+    is_high: bool,
+    elapsed: f32,
+    // time of the last switch to high, a cycle runs from one to the next.
+    cycle_start: Option<f32>,
+    // extremes of the measurement in the cycle so far.
+    max: f32,
+    min: f32,
+    cycles_seen: u8,
+    period_sum: f32,
+    amplitude_sum: f32,
+}
+
+impl RelayAutotune {
+    // cycles let go before measuring, the first is lopsided from the start.
+    const SETTLING_CYCLES: u8 = 2;
+
+    pub fn new(config: RelayConfig, setpoint: f32) -> Self {
+        RelayAutotune {
+            config,
+            setpoint,
+            is_high: true,
+            elapsed: 0.0,
+            cycle_start: None,
+            max: setpoint,
+            min: setpoint,
+            cycles_seen: 0,
+            period_sum: 0.0,
+            amplitude_sum: 0.0,
+        }
+    }
+
+    // the measurement now, dt seconds after the last call.
+    pub fn update(&mut self, measured: f32, dt: f32) -> RelayState {
+        self.elapsed += dt;
+        let error = self.setpoint - measured;
+        if F32(error).abs().0 > self.config.max_deviation {
+            return RelayState::Failed(AutotuneError::DeviationLimit);
+        }
+        if self.elapsed > self.config.max_duration {
+            return RelayState::Failed(AutotuneError::Timeout);
+        }
+        self.max = self.max.max(measured);
+        self.min = self.min.min(measured);
+
+        if self.is_high && error < -self.config.hysteresis {
+            self.is_high = false;
+        } else if !self.is_high && error > self.config.hysteresis {
+            self.is_high = true;
+            if let Some(result) = self.end_cycle() {
+                return RelayState::Done(result);
+            }
+        }
+
+        let output = if self.is_high {
+            self.config.amplitude
+        } else {
+            -self.config.amplitude
+        };
+        RelayState::Running(output)
+    }
+
+    fn end_cycle(&mut self) -> Option<RelayResult> {
+        if let Some(cycle_start) = self.cycle_start {
+            self.cycles_seen = self.cycles_seen.saturating_add(1);
+            if self.cycles_seen > Self::SETTLING_CYCLES {
+                self.period_sum += self.elapsed - cycle_start;
+                self.amplitude_sum += (self.max - self.min) / 2.0;
+            }
+        }
+        self.cycle_start = Some(self.elapsed);
+        self.max = self.setpoint;
+        self.min = self.setpoint;
+
+        let cycles = self.cycles_seen.saturating_sub(Self::SETTLING_CYCLES);
+        if cycles < self.config.cycles.max(1) {
+            return None;
+        }
+        let period = self.period_sum / cycles as f32;
+        let amplitude = self.amplitude_sum / cycles as f32;
+        // the describing function of a relay with hysteresis.
+        let hysteresis = self.config.hysteresis.min(0.9 * amplitude);
+        let amplitude = F32(amplitude * amplitude - hysteresis * hysteresis)
+            .sqrt()
+            .0;
+        Some(RelayResult {
+            ultimate_gain: 4.0 * self.config.amplitude / (consts::PI * amplitude),
+            ultimate_period: period,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: RelayConfig = RelayConfig {
+        amplitude: 1.0,
+        hysteresis: 0.1,
+        max_deviation: 5.0,
+        max_duration: 10.0,
+        cycles: 4,
+    };
+    const STEP: f32 = 0.000_1;
+
+    // a measurement oscillating around the set point, whatever the output.
+    fn sine(amplitude: f32, period: f32, t: f32) -> f32 {
+        amplitude * F32(consts::TAU * t / period).sin().0
+    }
+
+    fn run(relay: &mut RelayAutotune, measurement: impl Fn(f32) -> f32) -> RelayState {
+        let mut t = 0.0;
+        loop {
+            t += STEP;
+            match relay.update(measurement(t), STEP) {
+                RelayState::Running(_) => continue,
+                state => return state,
+            }
+        }
+    }
+
+    #[test]
+    fn switches_past_the_hysteresis() {
+        let mut relay = RelayAutotune::new(CONFIG, 0.0);
+        assert_eq!(relay.update(0.0, STEP), RelayState::Running(1.0));
+        assert_eq!(relay.update(0.09, STEP), RelayState::Running(1.0));
+        assert_eq!(relay.update(0.11, STEP), RelayState::Running(-1.0));
+        assert_eq!(relay.update(-0.09, STEP), RelayState::Running(-1.0));
+        assert_eq!(relay.update(-0.11, STEP), RelayState::Running(1.0));
+    }
+
+    #[test]
+    fn measures_the_ultimate_gain_and_period() {
+        let mut relay = RelayAutotune::new(CONFIG, 0.0);
+        let result = match run(&mut relay, |t| sine(2.0, 0.5, t)) {
+            RelayState::Done(result) => result,
+            state => panic!("{state:?}"),
+        };
+        let ultimate_gain = 4.0 / (consts::PI * F32(2.0 * 2.0 - 0.1 * 0.1).sqrt().0);
+        assert!((result.ultimate_gain - ultimate_gain).abs() < 0.01 * ultimate_gain);
+        assert!((result.ultimate_period - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn measures_around_the_set_point() {
+        let mut relay = RelayAutotune::new(CONFIG, 10.0);
+        let result = match run(&mut relay, |t| 10.0 + sine(1.0, 1.0, t)) {
+            RelayState::Done(result) => result,
+            state => panic!("{state:?}"),
+        };
+        assert!((result.ultimate_period - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn gives_up_past_the_max_deviation() {
+        let mut relay = RelayAutotune::new(CONFIG, 0.0);
+        assert_eq!(
+            run(&mut relay, |t| sine(6.0, 0.5, t)),
+            RelayState::Failed(AutotuneError::DeviationLimit)
+        );
+    }
+
+    #[test]
+    fn gives_up_after_the_max_duration() {
+        // never gets past the hysteresis, as if stuck on friction.
+        let mut relay = RelayAutotune::new(CONFIG, 0.0);
+        assert_eq!(
+            run(&mut relay, |t| sine(0.05, 0.5, t)),
+            RelayState::Failed(AutotuneError::Timeout)
+        );
+        // and oscillating too slowly to get enough cycles in.
+        let mut relay = RelayAutotune::new(CONFIG, 0.0);
+        assert_eq!(
+            run(&mut relay, |t| sine(1.0, 2.0, t)),
+            RelayState::Failed(AutotuneError::Timeout)
+        );
+    }
+
+    #[test]
+    fn tuning_rules() {
+        let result = RelayResult {
+            ultimate_gain: 10.0,
+            ultimate_period: 0.4,
+        };
+        let gains = TuningRule::ZieglerNichols.gains(&result);
+        assert_eq!(gains.kp, 6.0);
+        assert!((gains.ki - 6.0 / 0.2).abs() < 1e-4);
+        assert!((gains.kd - 6.0 * 0.05).abs() < 1e-6);
+
+        let gains = TuningRule::TyreusLuyben.gains(&result);
+        assert!((gains.kp - 10.0 / 2.2).abs() < 1e-5);
+        assert!((gains.ki - 10.0 / 2.2 / 0.88).abs() < 1e-4);
+        assert!((gains.kd - 10.0 / 2.2 * 0.4 / 6.3).abs() < 1e-6);
+
+        let gains = TuningRule::NoOvershoot.gains(&result);
+        assert!((gains.kp - 2.0).abs() < 1e-6);
+        assert!((gains.ki - 2.0 / 0.2).abs() < 1e-4);
+        assert!((gains.kd - 2.0 * 0.4 / 3.0).abs() < 1e-6);
+    }
+}
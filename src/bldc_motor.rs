@@ -1,14 +1,23 @@
 use core::f32::consts;
-use defmt::info;
+use embedded_storage::nor_flash::NorFlash;
 use micromath::F32;
 
+use crate::autotune::{AutotuneError, RelayAutotune, RelayConfig, RelayState, TuningRule};
+use crate::cogging::CoggingCompensation;
+use crate::common::clock::{Clock, Instant};
 use crate::common::em;
-use crate::pid::PID;
-use crate::sensor::{RotarySensor, RotorState};
-use crate::{driver, FOCMotor};
+use crate::current_control::CurrentController;
+use crate::motion::MotionProfile;
+use crate::pid::{PIDGains, PID};
+use crate::sensor::correction::{SensorCorrection, SensorResiduals};
+use crate::sensor::estimator::AngleEstimator;
+use crate::sensor::flux_observer::FluxObserver;
+use crate::sensor::{CurrentSensor, NoCurrentSensor, RotarySensor, RotorState, RotorTracker};
+use crate::storage::{self, MotorConfig, StorageError};
+use crate::{driver, ControlMode, FOCMotor};
 
 // Physical parameter of the motor that are useful for more advanced control.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct BLDCMotorSpecification {
     // number of electrical cycles per mechanical cycle
     pub pole_pairs: u8,
@@ -19,47 +28,216 @@ pub struct BLDCMotorSpecification {
     pub phase_inductance: f32,
 }
 
+impl BLDCMotorSpecification {
+    // volt seconds per electrical radian, peak per phase.
+    // kv is taken to be the no load speed against the peak line to line voltage.
+    pub fn flux_linkage(&self) -> f32 {
+        60.0 / (consts::TAU * 1.732_050_8 * self.kv as f32 * self.pole_pairs as f32)
+    }
+}
+
+// How the sensor lines up with the motor, found by `calibrate_rotary_sensor`.
+// Only depends on the build, so it can be kept and applied again at every power up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationResult {
+    pub pole_pairs: u8,
+    // the sensor counted down while the field turned forward.
+    // a sensor facing the other way and two phases swapped look the same from here and are fixed the same way,
+    // so this is both the sensor direction and the phase order.
+    pub is_reversed: bool,
+    // sensor angle in radians where the electrical angle is 0.
+    pub electrical_zero: f32,
+    // what is left of the sensor's error once the straight line is taken out.
+    pub correction: Option<SensorCorrection>,
+}
+
+// What the inner most loop controls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorqueControl {
+    // q voltage, torque is only roughly proportional to it and falls off with speed.
+    Voltage,
+    // q current, which torque is proportional to. Requires current sensing.
+    Current,
+}
+
+// Which of the cascaded loops `autotune` tunes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TunedLoop {
+    // rotor angle, with the velocity loop closed under it.
+    Angle,
+    // rotor speed, driving the q effort directly.
+    Velocity,
+}
+
 // One type of motor that can employ FOC are the BLDC motors.
 // This is the implementation of it.
-pub struct BLDCMotor<'a, B: driver::BLDCDriver, R: RotarySensor> {
+pub struct BLDCMotor<
+    'a,
+    B: driver::BLDCDriver,
+    A: RotorTracker,
+    T: Clock,
+    C: CurrentSensor = NoCurrentSensor,
+> {
     pub specification: BLDCMotorSpecification,
     pub driver: B,
-    pub angle: Option<RotorState<'a, R>>,
-    pub pid: PID<'a>,
+    // a sensor in a RotorState, or an observer for sensorless control.
+    pub angle: Option<A>,
+    // cascaded control, angle error to velocity to torque.
+    pub angle_pid: PID<'a, T>,
+    pub velocity_pid: PID<'a, T>,
+    // largest speed the angle loop can ask for, rad/s.
+    pub velocity_limit: f32,
+    // largest voltage that is applied to the motor.
+    // the current limit lives in the current controller.
+    pub voltage_limit: f32,
+    // voltage used to drag the rotor around when running open loop.
+    // the motor draws this all the time, so it is kept separate from the closed loop limit.
+    pub open_loop_voltage_limit: f32,
+    // the outer loops only need to run every so many foc loops.
+    pub angle_loop_divider: u32,
+    pub velocity_loop_divider: u32,
+
+    pub torque_control: TorqueControl,
+    pub current_sensor: Option<C>,
+    pub current_controller: Option<CurrentController<'a, T>>,
+    // added to the q effort by rotor angle, measured with `measure_cogging`.
+    pub cogging_compensation: Option<&'a CoggingCompensation>,
+    // moves the angle target there within limits, instead of all at once.
+    pub motion_profile: Option<MotionProfile>,
+    // q effort per rad/s^2 of the profile, about the inertia over the torque constant.
+    // 0 leaves the acceleration to the loops.
+    pub acceleration_feedforward: f32,
+
+    control_mode: ControlMode,
+    // meaning depends on the control mode.
+    target: f32,
+    clock: &'a T,
+    prior_loop: Instant,
+    // where the rotor is assumed to be when running open loop.
+    open_loop_rads: f32,
+    // number of foc loops run, for running the outer loops at a lower rate.
+    loop_count: u32,
+    // output of the velocity loop, held until it runs again.
+    throttle: f32,
 }
 
 // An incomplete and overly specific constructor.
-// TODO: complete the constructor with the following three task.
-//       detect and compensate orientation of driver wireing
-//       detect and compensate orientation of sensor
-//       detect and compensate sensor 0 position is different from driver 0 position
-
-//       add cogging compensation.
-//       add kalman filtering to sensor.
-//       add pid autotune.
-impl<'a, B: driver::BLDCDriver, R: RotarySensor> BLDCMotor<'a, B, R> {
+// Wiring, sensor orientation and offset are found by `calibrate_rotary_sensor`,
+// resistance, inductance and kv by `identify_parameters` when there is current sensing,
+// and the loop gains by `autotune`.
+impl<'a, B: driver::BLDCDriver, A: RotorTracker, T: Clock> BLDCMotor<'a, B, A, T> {
     pub fn new(
         specification: BLDCMotorSpecification,
-        rotor_angle: Option<RotorState<'a, R>>,
+        rotor_angle: Option<A>,
         driver: B,
-        pid: PID<'a>,
-    ) -> BLDCMotor<'a, B, R> {
+        angle_pid: PID<'a, T>,
+        velocity_pid: PID<'a, T>,
+    ) -> BLDCMotor<'a, B, A, T> {
+        let clock = angle_pid.clock;
+        let voltage_limit = 0.2 * driver.get_voltage_limit();
         BLDCMotor {
             specification,
             angle: rotor_angle,
             driver,
-            pid,
+            angle_pid,
+            velocity_pid,
+            velocity_limit: 20.0,
+            voltage_limit,
+            open_loop_voltage_limit: voltage_limit,
+            angle_loop_divider: 1,
+            velocity_loop_divider: 1,
+
+            torque_control: TorqueControl::Voltage,
+            current_sensor: None,
+            current_controller: None,
+            cogging_compensation: None,
+            motion_profile: None,
+            acceleration_feedforward: 0.0,
+
+            control_mode: ControlMode::Angle,
+            target: 0.0,
+            clock,
+            prior_loop: clock.now(),
+            open_loop_rads: 0.0,
+            loop_count: 0,
+            throttle: 0.0,
         }
     }
 
-    // calibrate the rotary sensor.
-    // requires a rotary sensor and a motor driver.
-    pub fn calibrate_rotary_sensor(&mut self) {
+    // Add current sensing and switch to controlling the current.
+    // The velocity loop then gives out the q current wanted instead of a voltage.
+    pub fn with_current_sensing<C: CurrentSensor>(
+        self,
+        current_sensor: C,
+        current_controller: CurrentController<'a, T>,
+    ) -> BLDCMotor<'a, B, A, T, C> {
+        BLDCMotor {
+            specification: self.specification,
+            angle: self.angle,
+            driver: self.driver,
+            angle_pid: self.angle_pid,
+            velocity_pid: self.velocity_pid,
+            velocity_limit: self.velocity_limit,
+            voltage_limit: self.voltage_limit,
+            open_loop_voltage_limit: self.open_loop_voltage_limit,
+            angle_loop_divider: self.angle_loop_divider,
+            velocity_loop_divider: self.velocity_loop_divider,
+
+            torque_control: TorqueControl::Current,
+            current_sensor: Some(current_sensor),
+            current_controller: Some(current_controller),
+            cogging_compensation: self.cogging_compensation,
+            motion_profile: self.motion_profile,
+            acceleration_feedforward: self.acceleration_feedforward,
+
+            control_mode: self.control_mode,
+            target: self.target,
+            clock: self.clock,
+            prior_loop: self.prior_loop,
+            open_loop_rads: self.open_loop_rads,
+            loop_count: self.loop_count,
+            throttle: self.throttle,
+        }
+    }
+}
+
+impl<'a, B: driver::BLDCDriver, R: RotarySensor, T: Clock, E: AngleEstimator, C: CurrentSensor>
+    BLDCMotor<'a, B, RotorState<'a, R, T, E>, T, C>
+{
+    // Find how the sensor lines up with the motor, apply it and give it back.
+    // The field is stepped forward and back over at least a full revolution while the sensor is read,
+    // the slope of the sensor against the field gives the pole pairs and the direction,
+    // the intercept the electrical zero and what is left over the sensor correction.
+    // The result can be stored and applied to a fresh motor with `apply_calibration`.
+    // requires a rotary sensor and a motor driver, gives nothing if the rotor did not follow the field,
+    // and then whatever calibration was applied before is kept.
+    pub fn calibrate_rotary_sensor(&mut self) -> Option<CalibrationResult> {
         // No point in calibrating the sensor is the sensor doesn't exist.
-        if self.angle.is_none() {
-            return;
+        let angle = self.angle.as_mut()?;
+        let (is_correct_direction, electrical_zero) = angle.get_return_mapping();
+        let correction = angle.get_correction().copied();
+        // measure the sensor as it is, so the result does not depend on what was applied before.
+        angle.reset_return_mapping();
+        angle.set_correction(None);
+
+        let result = self.find_calibration();
+        // save some power
+        self.driver.off();
+
+        match result {
+            Some(result) => self.apply_calibration(&result),
+            None => {
+                if let Some(angle) = self.angle.as_mut() {
+                    angle.set_correction(correction);
+                    angle.set_return_mapping(is_correct_direction, electrical_zero);
+                }
+            }
         }
+        result
+    }
 
+    // the measurement for `calibrate_rotary_sensor`, with the mapping and correction of the sensor reset.
+    fn find_calibration(&mut self) -> Option<CalibrationResult> {
         // linear regression, the formula and explanation can be found here
         // https://en.wikipedia.org/wiki/Simple_linear_regression#Normality_assumption
         // y=mx+b where y is the measured angle and x is the input target angle.
@@ -68,181 +246,900 @@ impl<'a, B: driver::BLDCDriver, R: RotarySensor> BLDCMotor<'a, B, R> {
         let mut xx: f32 = 0.0;
         let mut y: f32 = 0.0;
         let mut xy: f32 = 0.0;
+        // what the line does not explain, by reading, for the correction table.
+        let mut residuals = SensorResiduals::new();
 
-        // try for "some number" of electrical cycles
-        let e_rev = 10;
+        // try for "some number" of electrical cycles, and until the rotor has made a full revolution
+        // so the correction table is complete, the pole pairs are not known yet.
+        let min_e_rev = 10;
+        let max_e_rev = 64;
         // each cycle try "some number" of increments
         let tick_per_e_rev = 18;
         // smaller numbers are faster, larger numbers are more accurate
-        for i in 0..(e_rev * tick_per_e_rev) {
+        let mut sample = |motor: &mut Self, i: usize| -> Option<f32> {
             let target_rad = (i as f32) * consts::TAU / (tick_per_e_rev as f32);
-            let field_voltage = em::Vqd {
-                q: 0.0,
-                d: self.driver.get_voltage_limit(),
-            };
-            self.driver.set_rrf_voltage(field_voltage, target_rad);
-
-            // wait until rotor stops moving
-            self.angle.as_mut().unwrap().update();
-            let mut previous = self.angle.as_ref().unwrap().get_rads();
-            let mut count = 0;
-            while count < 50 {
-                self.angle.as_mut().unwrap().update();
-                let test = self.angle.as_ref().unwrap().get_rads();
-                // f32 cannot be exactly the same, but close enough for a period of time would be good enough.
-                if F32(test - previous).abs().0 < 0.002 {
-                    count += 1;
-                } else {
-                    count -= if count > 2 { 2 } else { count };
-                }
-                previous = 0.9 * previous + 0.1 * test;
-            }
-
-            self.angle.as_mut().unwrap().update();
-            let mech_rad = self.angle.as_ref().unwrap().get_rads();
+            let (reading, mech_rad) = motor.hold_field_at(target_rad)?;
+            residuals.add(reading, target_rad, mech_rad);
 
             n += 1.0;
             x += target_rad;
             xx += target_rad * target_rad;
             y += mech_rad;
             xy += target_rad * mech_rad;
-        }
+            Some(mech_rad)
+        };
 
+        let mut ticks = 0;
+        let mut start_rad = None;
+        loop {
+            let mech_rad = sample(self, ticks)?;
+            let start_rad = *start_rad.get_or_insert(mech_rad);
+            ticks += 1;
+            let is_full_revolution = F32(mech_rad - start_rad).abs().0 >= consts::TAU;
+            if ticks >= max_e_rev * tick_per_e_rev
+                || (ticks >= min_e_rev * tick_per_e_rev && is_full_revolution)
+            {
+                break;
+            }
+        }
         // repeat the previous loop again take out the effect of hysterisis.
-        for i in (0..(e_rev * tick_per_e_rev)).rev() {
-            let target_rad = (i as f32) * consts::TAU / (tick_per_e_rev as f32);
-            let field_voltage = em::Vqd {
-                q: 0.0,
-                d: self.driver.get_voltage_limit(),
-            };
-            self.driver.set_rrf_voltage(field_voltage, target_rad);
-
-            // wait until rotor stops moving
-            self.angle.as_mut().unwrap().update();
-            let mut previous = self.angle.as_ref().unwrap().get_rads();
-            let mut count = 0;
-            while count < 50 {
-                self.angle.as_mut().unwrap().update();
-                let test = self.angle.as_ref().unwrap().get_rads();
-                // f32 cannot be exactly the same, but close enough for a period of time would be good enough.
-                if F32(test - previous).abs().0 < 0.002 {
-                    count += 1;
+        for i in (0..ticks).rev() {
+            sample(self, i)?;
+        }
+
+        let m = (n * xy - x * y) / (n * xx - x * x); // this is 1 / pole pair
+        let b = (xx * y - x * xy) / (n * xx - x * x);
+        // a rotor that did not follow the field says nothing.
+        if !m.is_finite() || F32(m).abs().0 < 1.0 / 255.0 {
+            info!("calibration failed, the rotor did not follow the field");
+            return None;
+        }
+        // a wrong sensor or a bad fit gives a slope nowhere near a whole number of pole pairs.
+        let exact_pole_pairs = F32(1.0 / m).abs().0;
+        let pole_pairs = F32(exact_pole_pairs).round().0;
+        if pole_pairs < 1.0 || F32(exact_pole_pairs - pole_pairs).abs().0 > 0.25 {
+            info!(
+                "calibration failed, {} pole pairs is not plausible",
+                exact_pole_pairs
+            );
+            return None;
+        }
+        let pole_pairs = pole_pairs as u8;
+        let k = b % (consts::TAU / pole_pairs as f32); // this is the smallest mechanical angle such that electrical angle is 0.
+        info!("s*pp {}, k {}", 1.0 / m, k);
+
+        Some(CalibrationResult {
+            pole_pairs,
+            is_reversed: m < 0.0,
+            electrical_zero: k,
+            // the mapping was reset, so the readings were taken as they are.
+            correction: residuals.to_correction(m, b, true),
+        })
+    }
+
+    // Set up the motor and its rotor state from an earlier calibration, in place of whatever was there.
+    pub fn apply_calibration(&mut self, result: &CalibrationResult) {
+        self.specification.pole_pairs = result.pole_pairs;
+        if let Some(angle) = self.angle.as_mut() {
+            // the correction is by raw reading, so the mapping makes no difference to it.
+            angle.set_correction(result.correction);
+            angle.reset_return_mapping();
+            angle.set_return_mapping(!result.is_reversed, result.electrical_zero);
+        }
+    }
+
+    // Everything calibration and tuning found, to be kept with `storage::save`.
+    pub fn get_config(&self) -> Option<MotorConfig> {
+        let angle = self.angle.as_ref()?;
+        let (is_correct_direction, electrical_zero) = angle.get_return_mapping();
+        let current_gains = self.current_controller.as_ref().map(|controller| PIDGains {
+            kp: controller.pid_q.kp,
+            ki: controller.pid_q.ki,
+            kd: 0.0,
+        });
+        Some(MotorConfig {
+            specification: self.specification,
+            calibration: CalibrationResult {
+                pole_pairs: self.specification.pole_pairs,
+                is_reversed: !is_correct_direction,
+                electrical_zero,
+                correction: angle.get_correction().copied(),
+            },
+            angle_gains: self.angle_pid.get_gains(),
+            velocity_gains: self.velocity_pid.get_gains(),
+            current_gains,
+            has_cogging_compensation: self.cogging_compensation.is_some(),
+        })
+    }
+
+    // Put back what `storage::load` read, in place of calibrating and tuning again.
+    // The cogging table is loaded on its own and set in `cogging_compensation`.
+    pub fn apply_config(&mut self, config: &MotorConfig) {
+        self.specification = config.specification;
+        self.apply_calibration(&config.calibration);
+        self.angle_pid.set_gains(&config.angle_gains);
+        self.velocity_pid.set_gains(&config.velocity_gains);
+        // gains for a current loop that is not there yet would be lost anyway,
+        // and a current loop keeps its own if none were saved.
+        if let (Some(controller), Some(gains)) =
+            (self.current_controller.as_mut(), config.current_gains)
+        {
+            for pid in [&mut controller.pid_q, &mut controller.pid_d] {
+                pid.kp = gains.kp;
+                pid.ki = gains.ki;
+            }
+        }
+    }
+
+    // What to do at power up, in place of calibrating every time.
+    // Applies the config saved at offset with `apply_config`, and its cogging table if it has one and a table is given.
+    // When there is nothing usable there the sensor is calibrated, and that is saved for the next power up.
+    // The cogging table is not measured here, so then it is saved without one and the given table is left alone,
+    // save again after `measure_cogging` to keep one.
+    // Gives back the calibration in use, nothing if calibrating was needed and failed,
+    // and the error if the flash could not be read, so a good record is not written over because of it.
+    pub fn load_config_or_calibrate<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        offset: u32,
+        mut cogging_compensation: Option<&'a mut CoggingCompensation>,
+    ) -> Result<Option<CalibrationResult>, StorageError> {
+        match storage::load(flash, offset, cogging_compensation.as_deref_mut()) {
+            Ok(config) => {
+                self.apply_config(&config);
+                if config.has_cogging_compensation {
+                    self.cogging_compensation =
+                        cogging_compensation.map(|table| -> &'a CoggingCompensation { table });
+                }
+                return Ok(Some(config.calibration));
+            }
+            Err(StorageError::NotFound | StorageError::Version(_) | StorageError::Crc) => {}
+            Err(error) => return Err(error),
+        }
+
+        info!("no saved config, calibrating");
+        let calibration = match self.calibrate_rotary_sensor() {
+            Some(calibration) => calibration,
+            None => return Ok(None),
+        };
+        if let Some(mut config) = self.get_config() {
+            config.has_cogging_compensation = false;
+            if storage::save(flash, offset, &config, None).is_err() {
+                info!("could not save the calibration");
+            }
+        }
+        Ok(Some(calibration))
+    }
+
+    // Pull the rotor to the electrical angle with a d voltage and wait until it stops moving.
+    // Gives back the raw reading and the angle it settled at.
+    fn hold_field_at(&mut self, electrical_angle: f32) -> Option<(u16, f32)> {
+        let field_voltage = em::Vqd {
+            q: 0.0,
+            d: self.driver.get_voltage_limit(),
+        };
+        self.driver.set_rrf_voltage(field_voltage, electrical_angle);
+
+        // wait until rotor stops moving
+        let angle = self.angle.as_mut()?;
+        angle.update();
+        let mut previous = angle.get_rads();
+        let mut count = 0;
+        while count < 50 {
+            angle.update();
+            let test = angle.get_rads();
+            // f32 cannot be exactly the same, but close enough for a period of time would be good enough.
+            if F32(test - previous).abs().0 < 0.002 {
+                count += 1;
+            } else {
+                count -= if count > 2 { 2 } else { count };
+            }
+            previous = 0.9 * previous + 0.1 * test;
+        }
+
+        angle.update();
+        Some((angle.get_reading(), angle.get_rads()))
+    }
+}
+
+impl<'a, B: driver::BLDCDriver, T: Clock, C: CurrentSensor>
+    BLDCMotor<'a, B, FluxObserver<'a, T>, T, C>
+{
+    // Get a sensorless motor spinning.
+    // The field is forced around with a fixed current (I-f) until the observer has enough
+    // back emf to lock on, then the velocity loop takes over with the given target in rad/s.
+    // Gives false and turns the driver off if the observer has not taken over within the startup timeout,
+    // a rotor that is jammed or does not follow the field never gets there.
+    pub fn start_sensorless(&mut self, velocity_target: f32) -> bool {
+        let (startup_current, startup_timeout) = match self.angle.as_mut() {
+            Some(observer) => {
+                observer.start();
+                let config = observer.get_config();
+                (config.startup_current, config.startup_timeout)
+            }
+            None => return false,
+        };
+
+        self.set_control_mode(ControlMode::CurrentTorque);
+        self.set_target(startup_current);
+        let start = self.clock.now();
+        while !self
+            .angle
+            .as_ref()
+            .is_some_and(|observer| observer.is_observing())
+        {
+            if ((self.clock.now() - start).to_micros() as f32) > startup_timeout * 1_000_000.0 {
+                info!("sensorless startup failed, the observer did not lock on");
+                self.set_target(0.0);
+                self.driver.off();
+                return false;
+            }
+            self.foc_loop();
+        }
+
+        self.set_control_mode(ControlMode::Velocity);
+        self.set_target(velocity_target);
+        true
+    }
+}
+
+impl<B: driver::BLDCDriver, A: RotorTracker, T: Clock, C: CurrentSensor> BLDCMotor<'_, B, A, T, C> {
+    // start the mode from where the rotor is, so nothing jumps.
+    fn start_control_mode(&mut self, mode: ControlMode) {
+        let (rads, rads_per_s) = match self.angle.as_ref() {
+            Some(angle) => (angle.get_rads(), angle.get_rads_per_s()),
+            None => (self.open_loop_rads, 0.0),
+        };
+        self.target = match mode {
+            ControlMode::Angle | ControlMode::OpenLoopAngle => rads,
+            ControlMode::Velocity | ControlMode::OpenLoopVelocity => rads_per_s,
+            ControlMode::VoltageTorque | ControlMode::CurrentTorque => 0.0,
+        };
+        self.open_loop_rads = rads;
+        self.prior_loop = self.clock.now();
+        if let Some(motion_profile) = self.motion_profile.as_mut() {
+            motion_profile.reset(rads, self.prior_loop);
+        }
+
+        self.angle_pid.reset();
+        self.angle_pid.set(rads);
+        self.velocity_pid.reset();
+        self.velocity_pid.set(rads_per_s);
+        self.throttle = 0.0;
+        if let Some(current_controller) = self.current_controller.as_mut() {
+            current_controller.reset();
+        }
+        self.control_mode = mode;
+    }
+
+    // Turn the output of the outer loops into a field voltage and apply it.
+    // throttle is a q voltage or a q current depending on torque_control.
+    // Gives back the voltage applied, if any.
+    fn set_torque(
+        &mut self,
+        throttle: f32,
+        torque_control: TorqueControl,
+        electrical_angle: f32,
+        rads_per_s: f32,
+        i_abc: Option<&em::Iabc>,
+    ) -> Option<em::Vqd> {
+        let voltage_limit = self.voltage_limit;
+
+        let field_voltage = match (torque_control, i_abc, self.current_controller.as_mut()) {
+            (TorqueControl::Current, Some(i_abc), Some(current_controller)) => {
+                current_controller.update(throttle, i_abc, electrical_angle, voltage_limit)
+            }
+            // nothing to regulate without a reading, keep the last voltage.
+            (TorqueControl::Current, None, Some(_)) => return None,
+            (TorqueControl::Current, _, None) => {
+                // without current sensing, the best guess is the resistive drop.
+                let voltage = throttle * self.specification.phase_resistance;
+                em::Vqd {
+                    q: voltage.clamp(-voltage_limit, voltage_limit),
+                    d: 0.0,
+                }
+            }
+            (TorqueControl::Voltage, _, _) => {
+                // Convert desired throttle to the field voltage desired.
+                let throttle: f32 = if throttle > voltage_limit {
+                    voltage_limit
+                } else if throttle < -voltage_limit {
+                    -voltage_limit
                 } else {
-                    count -= if count > 2 { 2 } else { count };
+                    throttle
+                };
+
+                let mtpv_voltage_angle_rad = F32(rads_per_s * self.specification.phase_inductance
+                    / self.specification.phase_resistance)
+                .atan()
+                .0 + 0.1
+                    * F32(rads_per_s * self.specification.phase_inductance
+                        / self.specification.phase_resistance
+                        / 20.0)
+                    .atan()
+                    .powi(3)
+                    .0;
+
+                em::Vqd {
+                    // q should be in the direction of the throttle.
+                    q: throttle * F32(mtpv_voltage_angle_rad).cos().0,
+                    // d should be 0 or negative for maximum torque per volt.
+                    d: -throttle * F32(mtpv_voltage_angle_rad).sin().0,
                 }
-                previous = 0.9 * previous + 0.1 * test;
             }
+        };
 
-            self.angle.as_mut().unwrap().update();
-            let mech_rad = self.angle.as_ref().unwrap().get_rads();
+        self.driver.set_rrf_voltage(field_voltage, electrical_angle);
+        Some(field_voltage)
+    }
 
-            n += 1.0;
-            x += target_rad;
-            xx += target_rad * target_rad;
-            y += mech_rad;
-            xy += target_rad * mech_rad;
+    // Spin or place the field where the rotor should be, without looking at where it is.
+    // Needs nothing but the clock, so it works before a sensor is fitted.
+    fn open_loop(&mut self, dt: f32) {
+        match self.control_mode {
+            ControlMode::OpenLoopVelocity => self.open_loop_rads += self.target * dt,
+            _ => {
+                // step toward the target no faster than the rotor can be expected to follow.
+                let max_step = self.velocity_limit * dt;
+                self.open_loop_rads +=
+                    (self.target - self.open_loop_rads).clamp(-max_step, max_step);
+            }
         }
 
-        // save some power
+        // d voltage pulls the rotor onto the field, the same as calibration does.
+        let electrical_angle = self.open_loop_rads * (self.specification.pole_pairs as f32);
+        let field_voltage = em::Vqd {
+            q: 0.0,
+            d: self.open_loop_voltage_limit,
+        };
+        self.driver.set_rrf_voltage(field_voltage, electrical_angle);
+    }
+}
+
+impl<B: driver::BLDCDriver, A: RotorTracker, T: Clock, C: CurrentSensor> BLDCMotor<'_, B, A, T, C> {
+    // Measure the cogging of one revolution into the table, at so many evenly spaced angles.
+    // The rotor is held at each angle by the angle loop and the q effort it settles to is recorded,
+    // once going forward and once going back so friction cancels out.
+    // The table is then smoothed over `smoothing` entries, see `CoggingCompensation::smooth`.
+    // Needs a rotor tracker and an angle loop with enough integral to hold still.
+    // The motion profile is left out while measuring, and the control mode and target are put back afterward.
+    pub fn measure_cogging(
+        &mut self,
+        table: &mut CoggingCompensation,
+        samples: usize,
+        smoothing: usize,
+    ) {
+        let start = match self.angle.as_ref() {
+            Some(angle) => F32(angle.get_rads() / consts::TAU).floor().0 * consts::TAU,
+            None => return,
+        };
+        let samples = samples.clamp(1, table.get_table().len());
+
+        let control_mode = self.control_mode;
+        let target = self.target;
+        // the loops have to find the whole effort on their own, and go straight to each angle.
+        let cogging_compensation = self.cogging_compensation.take();
+        let motion_profile = self.motion_profile.take();
+        self.start_control_mode(ControlMode::Angle);
+
+        for sample in 0..samples {
+            let target = start + sample as f32 * consts::TAU / samples as f32;
+            let effort = self.settle_and_get_effort(target);
+            table.set_sample(sample, samples, effort);
+        }
+        for sample in (0..samples).rev() {
+            let target = start + sample as f32 * consts::TAU / samples as f32;
+            let effort = self.settle_and_get_effort(target);
+            let forward_effort = table.get_sample(sample, samples);
+            table.set_sample(sample, samples, (forward_effort + effort) / 2.0);
+        }
+
+        table.fill_from_samples(samples);
+        table.smooth(smoothing);
+        self.cogging_compensation = cogging_compensation;
+        self.motion_profile = motion_profile;
         self.driver.off();
+        self.start_control_mode(control_mode);
+        self.set_target(target);
+    }
 
-        let m = (n * xy - x * y) / (n * xx - x * x); // this is 1 / pole pair
-        let k = ((xx * y - x * xy) / (n * xx - x * x))
-            % (consts::TAU / self.specification.pole_pairs as f32); // this is the smallest mechanical angle such that electrical angle is 0.
-        info!("s*pp {}, k {}", 1.0 / m, k);
-        self.specification.pole_pairs = F32(m).abs().round().0 as u8;
-        self.angle.as_mut().unwrap().set_return_mapping(m > 0.0, k);
+    // run the loops until the rotor holds still at the target, then average the effort holding it there.
+    fn settle_and_get_effort(&mut self, target: f32) -> f32 {
+        self.set_target(target);
+        let mut count = 0;
+        let mut loops = 0;
+        // give up on settling eventually, a sticky rotor may never be within tolerance.
+        while count < 200 && loops < 20_000 {
+            self.foc_loop();
+            loops += 1;
+            let (rads, rads_per_s) = match self.angle.as_ref() {
+                Some(angle) => (angle.get_rads(), angle.get_rads_per_s()),
+                None => return 0.0,
+            };
+            if F32(rads - target).abs().0 < 0.005 && F32(rads_per_s).abs().0 < 0.05 {
+                count += 1;
+            } else {
+                count -= if count > 2 { 2 } else { count };
+            }
+        }
+
+        let mut effort = 0.0;
+        for _ in 0..100 {
+            self.foc_loop();
+            effort += self.throttle;
+        }
+        effort / 100.0
+    }
+}
+
+impl<B: driver::BLDCDriver, A: RotorTracker, T: Clock, C: CurrentSensor> BLDCMotor<'_, B, A, T, C> {
+    // Measure the phase resistance, the phase inductance and the kv, write them into the specification
+    // and give it back. Needs current sensing, gives nothing without it or if a measurement makes no sense.
+    // The field is held at the electrical zero for the resistance and inductance, the rotor lines up with it,
+    // then spun open loop at the given mechanical rad/s for the back emf.
+    // test_voltage should drive a safe current through the winding while standing still, and be enough to spin it.
+    // The pole pairs have to be right for the kv, calibrate first.
+    // The current controller is not retuned, make it again with `CurrentController::from_specification`.
+    pub fn identify_parameters(
+        &mut self,
+        test_voltage: f32,
+        spin_velocity: f32,
+    ) -> Option<BLDCMotorSpecification> {
+        self.current_sensor.as_ref()?;
+        let control_mode = self.control_mode;
+        // spinning changes it, and it has to come back however that ends.
+        let open_loop_voltage_limit = self.open_loop_voltage_limit;
+        let result = self.identify_with_current_sensor(test_voltage, spin_velocity);
+        self.open_loop_voltage_limit = open_loop_voltage_limit;
+        self.driver.off();
+        self.set_control_mode(control_mode);
+
+        let (phase_resistance, phase_inductance, flux_linkage) = result?;
+        info!(
+            "R {} ohm, L {} H, flux linkage {} Vs/rad",
+            phase_resistance, phase_inductance, flux_linkage
+        );
+        self.specification.phase_resistance = phase_resistance;
+        self.specification.phase_inductance = phase_inductance;
+        // the other way round from `BLDCMotorSpecification::flux_linkage`.
+        let kv = 60.0
+            / (consts::TAU * 1.732_050_8 * flux_linkage * self.specification.pole_pairs as f32);
+        self.specification.kv = kv.clamp(1.0, u16::MAX as f32) as u16;
+        Some(self.specification)
+    }
+
+    fn identify_with_current_sensor(
+        &mut self,
+        test_voltage: f32,
+        spin_velocity: f32,
+    ) -> Option<(f32, f32, f32)> {
+        let high = test_voltage;
+        let low = test_voltage / 2.0;
+
+        // resistance between two voltages, so whatever the driver drops on its own cancels out.
+        // the first hold is long enough for the rotor to swing onto the field and stop.
+        self.hold_d_voltage(low, 1.0);
+        let i_low = self.average_d_current(low, 0.2)?;
+        self.hold_d_voltage(high, 0.2);
+        let i_high = self.average_d_current(high, 0.2)?;
+        let phase_resistance = (high - low) / (i_high - i_low);
+        if !phase_resistance.is_finite() || phase_resistance <= 0.0 {
+            info!("identification failed, no current through the winding");
+            return None;
+        }
+
+        // inductance from the current stepping between the two, down and back up.
+        // v = R i + L di/dt, so L times the change in current is the integral of v - R i.
+        let fall = self.step_d_voltage(high, low, i_high, phase_resistance)?;
+        self.hold_d_voltage(low, 0.1);
+        let rise = self.step_d_voltage(low, high, i_low, phase_resistance)?;
+        let phase_inductance = (rise + fall) / 2.0;
+        if !phase_inductance.is_finite() || phase_inductance <= 0.0 {
+            info!("identification failed, the current did not rise like a winding");
+            return None;
+        }
+
+        let flux_linkage = self.spin_and_get_flux_linkage(
+            test_voltage,
+            spin_velocity,
+            phase_resistance,
+            phase_inductance,
+        )?;
+        Some((phase_resistance, phase_inductance, flux_linkage))
+    }
+
+    // d current read while holding the field at the electrical zero with the d voltage.
+    fn read_d_current(&mut self, voltage: f32) -> Option<f32> {
+        self.driver
+            .set_rrf_voltage(em::Vqd { q: 0.0, d: voltage }, 0.0);
+        // a sensor takes its time, so the loop runs about as fast as the foc loop would.
+        if let Some(angle) = self.angle.as_mut() {
+            angle.update();
+        }
+        let i_abc = self.current_sensor.as_mut()?.get_phase_currents().ok()?;
+        Some(i_abc.parks_transformation(0.0).d)
+    }
+
+    fn hold_d_voltage(&mut self, voltage: f32, seconds: f32) {
+        let start = self.clock.now();
+        while ((self.clock.now() - start).to_micros() as f32) < seconds * 1_000_000.0 {
+            self.driver
+                .set_rrf_voltage(em::Vqd { q: 0.0, d: voltage }, 0.0);
+            if let Some(angle) = self.angle.as_mut() {
+                angle.update();
+            }
+        }
+    }
+
+    fn average_d_current(&mut self, voltage: f32, seconds: f32) -> Option<f32> {
+        let start = self.clock.now();
+        let mut sum = 0.0;
+        let mut count = 0;
+        while count == 0 || ((self.clock.now() - start).to_micros() as f32) < seconds * 1_000_000.0
+        {
+            sum += self.read_d_current(voltage)?;
+            count += 1;
+        }
+        Some(sum / count as f32)
+    }
+
+    // Step the d voltage and integrate until the current has mostly got where it is going.
+    // Needs a few readings within the time constant L / R, with fewer the inductance reads high.
+    fn step_d_voltage(
+        &mut self,
+        from: f32,
+        to: f32,
+        i_from: f32,
+        phase_resistance: f32,
+    ) -> Option<f32> {
+        let i_to = i_from + (to - from) / phase_resistance;
+        let start = self.clock.now();
+        let mut prior_time = start;
+        let mut prior_i = i_from;
+        let mut integral = 0.0;
+        loop {
+            let i = self.read_d_current(to)?;
+            let now = self.clock.now();
+            let dt = (now - prior_time).to_micros() as f32 / 1_000_000.0;
+            // the voltage is the step from the start, the current the average over the reading.
+            integral += ((to - from) - phase_resistance * ((prior_i + i) / 2.0 - i_from)) * dt;
+            prior_time = now;
+            prior_i = i;
+
+            let is_settled = (i - i_from) / (i_to - i_from) > 0.9;
+            // far longer than any motor this drives, the current is not going anywhere.
+            let is_timed_out = (now - start).to_micros() > 100_000;
+            if is_settled || is_timed_out {
+                return Some(integral / (i - i_from));
+            }
+        }
+    }
+
+    // Spin the field open loop, and once the rotor follows take the back emf out of the voltage equation.
+    // In the frame of the field, steady state,
+    //      vq = R iq + w L id + eq
+    //      vd = R id - w L iq + ed
+    // and the size of e is the electrical speed times the flux linkage, whatever the rotor lags by.
+    fn spin_and_get_flux_linkage(
+        &mut self,
+        test_voltage: f32,
+        spin_velocity: f32,
+        phase_resistance: f32,
+        phase_inductance: f32,
+    ) -> Option<f32> {
+        self.open_loop_voltage_limit = test_voltage;
+        self.set_control_mode(ControlMode::OpenLoopVelocity);
+
+        // speed up slowly enough for the rotor to keep up, then let it settle.
+        let ramp_s = 2.0;
+        let start = self.clock.now();
+        loop {
+            let elapsed = (self.clock.now() - start).to_micros() as f32 / 1_000_000.0;
+            if elapsed > ramp_s + 0.5 {
+                break;
+            }
+            self.set_target(spin_velocity * (elapsed / ramp_s).min(1.0));
+            self.foc_loop();
+        }
+
+        let mut i_q = 0.0;
+        let mut i_d = 0.0;
+        let mut rads_per_s = 0.0;
+        let mut count = 0;
+        let start = self.clock.now();
+        while count == 0 || (self.clock.now() - start).to_micros() < 500_000 {
+            self.foc_loop();
+            let electrical_angle = self.open_loop_rads * self.specification.pole_pairs as f32;
+            let i_abc = self.current_sensor.as_mut()?.get_phase_currents().ok()?;
+            let i_qd = i_abc.parks_transformation(electrical_angle);
+            i_q += i_qd.q;
+            i_d += i_qd.d;
+            // a rotor dragged open loop swings around the speed of the field, so average it too.
+            if let Some(angle) = self.angle.as_ref() {
+                rads_per_s += angle.get_rads_per_s();
+            }
+            count += 1;
+        }
+        let (i_q, i_d) = (i_q / count as f32, i_d / count as f32);
+        let rads_per_s = rads_per_s / count as f32;
+
+        // a rotor that slipped is not turning at the speed of the field.
+        if self.angle.is_some()
+            && F32(rads_per_s - spin_velocity).abs().0 > 0.1 * F32(spin_velocity).abs().0
+        {
+            info!("identification failed, the rotor did not follow the field");
+            return None;
+        }
+
+        let electrical_speed = spin_velocity * self.specification.pole_pairs as f32;
+        let e_q = -phase_resistance * i_q - electrical_speed * phase_inductance * i_d;
+        let e_d = test_voltage - phase_resistance * i_d + electrical_speed * phase_inductance * i_q;
+        let flux_linkage = F32(e_q * e_q + e_d * e_d).sqrt().0 / F32(electrical_speed).abs().0;
+        if !flux_linkage.is_finite() || flux_linkage <= 0.0 {
+            return None;
+        }
+        Some(flux_linkage)
+    }
+}
+
+impl<B: driver::BLDCDriver, A: RotorTracker, T: Clock, C: CurrentSensor> BLDCMotor<'_, B, A, T, C> {
+    // Tune a loop by relay feedback, apply the gains and give them back.
+    // For the velocity loop the q effort is switched between plus and minus the relay amplitude,
+    // in volts or amps depending on the torque control, and the speed oscillates around the set point.
+    // For the angle loop the velocity target is switched instead, so tune the velocity loop first.
+    // A set point of 0 rad/s for the velocity loop, or where the rotor is for the angle loop, is easiest,
+    // the relay is symmetric and cannot hold a load on its own.
+    // The previous control mode is started again afterward, whether it worked or not.
+    pub fn autotune(
+        &mut self,
+        tuned_loop: TunedLoop,
+        setpoint: f32,
+        config: &RelayConfig,
+        rule: TuningRule,
+    ) -> Result<PIDGains, AutotuneError> {
+        if self.angle.is_none() {
+            return Err(AutotuneError::NoRotorTracker);
+        }
+        let control_mode = self.control_mode;
+        self.start_control_mode(match (tuned_loop, self.torque_control) {
+            (TunedLoop::Angle, _) => ControlMode::Velocity,
+            (TunedLoop::Velocity, TorqueControl::Voltage) => ControlMode::VoltageTorque,
+            (TunedLoop::Velocity, TorqueControl::Current) => ControlMode::CurrentTorque,
+        });
+
+        let mut relay = RelayAutotune::new(*config, setpoint);
+        let mut prior_time = self.clock.now();
+        let result = loop {
+            self.foc_loop();
+            let now = self.clock.now();
+            let dt = (now - prior_time).to_micros() as f32 / 1_000_000.0;
+            prior_time = now;
+
+            let measured = match (tuned_loop, self.angle.as_ref()) {
+                (TunedLoop::Angle, Some(angle)) => angle.get_rads(),
+                (TunedLoop::Velocity, Some(angle)) => angle.get_rads_per_s(),
+                (_, None) => break Err(AutotuneError::NoRotorTracker),
+            };
+            match relay.update(measured, dt) {
+                RelayState::Running(output) => self.set_target(output),
+                RelayState::Done(result) => break Ok(result),
+                RelayState::Failed(error) => break Err(error),
+            }
+        };
+
+        self.driver.off();
+        self.start_control_mode(control_mode);
+        let result = result?;
+        info!(
+            "ultimate gain {}, ultimate period {} s",
+            result.ultimate_gain, result.ultimate_period
+        );
+
+        let gains = rule.gains(&result);
+        match tuned_loop {
+            TunedLoop::Angle => self.angle_pid.set_gains(&gains),
+            TunedLoop::Velocity => self.velocity_pid.set_gains(&gains),
+        }
+        Ok(gains)
     }
 }
 
 // implement FOC control functions for BLDC motor
-impl<B: driver::BLDCDriver, R: RotarySensor> FOCMotor for BLDCMotor<'_, B, R> {
+impl<B: driver::BLDCDriver, A: RotorTracker, T: Clock, C: CurrentSensor> FOCMotor
+    for BLDCMotor<'_, B, A, T, C>
+{
+    fn set_control_mode(&mut self, mode: ControlMode) {
+        if mode == self.control_mode {
+            return;
+        }
+        self.start_control_mode(mode);
+    }
+
+    fn get_control_mode(&self) -> ControlMode {
+        self.control_mode
+    }
+
+    fn set_target(&mut self, target: f32) {
+        match self.control_mode {
+            // with a profile the angle loop follows it there, see `foc_loop`.
+            ControlMode::Angle => match self.motion_profile.as_mut() {
+                Some(motion_profile) => {
+                    let now = self.clock.now();
+                    // a profile at rest may have been put in after the mode started, so start it from the rotor.
+                    if let (true, Some(angle)) = (motion_profile.is_done(now), self.angle.as_ref())
+                    {
+                        motion_profile.reset(angle.get_rads(), now);
+                    }
+                    motion_profile.set_target(target, now);
+                }
+                None => self.angle_pid.set(target),
+            },
+            ControlMode::Velocity => self
+                .velocity_pid
+                .set(target.clamp(-self.velocity_limit, self.velocity_limit)),
+            _ => {}
+        }
+        self.target = target;
+    }
+
     // target is in radians
+    // without a sensor the rotor is dragged there open loop.
     fn goto(&mut self, target: f32) {
         if self.angle.is_some() {
-            self.pid.set(target);
+            self.set_control_mode(ControlMode::Angle);
+        } else {
+            self.set_control_mode(ControlMode::OpenLoopAngle);
         }
+        self.set_target(target);
     }
 
     // target is in radians
     fn goto_blocking(&mut self, target: f32) {
-        if self.angle.is_some() {
-            self.goto(target);
+        self.goto(target);
 
-            let mut counter = 0;
-            loop {
-                self.foc_loop();
+        let mut counter = 0;
+        loop {
+            self.foc_loop();
 
-                let e = self.angle.as_ref().unwrap().get_rads() - self.pid.sp;
+            // open loop can only tell where the field is, not the rotor.
+            let position = match self.angle.as_ref() {
+                Some(angle) => angle.get_rads(),
+                None => self.open_loop_rads,
+            };
+            let e = position - self.target;
 
-                // only if multiple the motor is close to target for extend time.
-                if F32(e).abs().0 < 0.005 {
-                    counter += 1;
-                } else {
-                    counter -= if counter > 1 { 1 } else { counter };
-                }
+            // only if multiple the motor is close to target for extend time.
+            if F32(e).abs().0 < 0.005 {
+                counter += 1;
+            } else {
+                counter -= if counter > 1 { 1 } else { counter };
+            }
 
-                if counter >= 100 {
-                    break;
-                }
+            if counter >= 100 {
+                break;
             }
         }
     }
 
     fn foc_loop(&mut self) {
+        let now = self.clock.now();
+        let dt = (now - self.prior_loop).to_micros() as f32 / 1_000_000.0;
+        self.prior_loop = now;
+
         // Update the rotor angle reading
-        if self.angle.is_some() {
-            self.angle.as_mut().unwrap().update();
+        if let Some(angle) = self.angle.as_mut() {
+            angle.update();
         }
 
-        let angle_state = self.angle.as_ref().unwrap();
-
-        // Use the angle differences to get an arbitrary unit of power that is desired to the motors.
-        let desired_throttle = self.pid.update_and_get_throttle(angle_state.get_rads());
-
-        // Convert desired throttle to the field voltage desired.
+        if let ControlMode::OpenLoopVelocity | ControlMode::OpenLoopAngle = self.control_mode {
+            self.open_loop(dt);
+            return;
+        }
 
-        // TODO: control field current instead as they relate to the torque.
-        //       This requires current sensing to be implemented first which does not exist.
-        //
+        // everything else needs to know where the rotor is.
+        let angle_state = match self.angle.as_ref() {
+            Some(angle_state) => angle_state,
+            None => {
+                self.driver.off();
+                return;
+            }
+        };
+        let rads = angle_state.get_rads();
+        let rads_per_s = angle_state.get_rads_per_s();
 
         // electrical angle is the rotor angle from electricity's perspective
         let electrical_angle =
             (angle_state.get_fract()) * (self.specification.pole_pairs as f32) * consts::TAU;
 
-        let voltage_limit = 0.2 * self.driver.get_voltage_limit();
+        // read once, both the current loop and a sensorless tracker want it.
+        let i_abc = match self.current_sensor.as_mut() {
+            Some(current_sensor) => current_sensor.get_phase_currents().ok(),
+            None => None,
+        };
 
-        let throttle: f32 = if desired_throttle > voltage_limit {
-            voltage_limit
-        } else if desired_throttle < -voltage_limit {
-            -voltage_limit
-        } else {
-            desired_throttle
+        let run_angle_loop = self
+            .loop_count
+            .is_multiple_of(self.angle_loop_divider.max(1));
+        let run_velocity_loop = self
+            .loop_count
+            .is_multiple_of(self.velocity_loop_divider.max(1));
+        self.loop_count = self.loop_count.wrapping_add(1);
+
+        // the limits belong to the motor, the loops have to know them so they do not wind up against them.
+        self.angle_pid.output_limit = self.velocity_limit;
+        self.velocity_pid.output_limit =
+            match (self.torque_control, self.current_controller.as_ref()) {
+                (TorqueControl::Voltage, _) => self.voltage_limit,
+                (TorqueControl::Current, Some(current_controller)) => {
+                    current_controller.current_limit
+                }
+                (TorqueControl::Current, None) => {
+                    self.voltage_limit / self.specification.phase_resistance
+                }
+            };
+
+        // angle error gives the speed wanted.
+        // where the profile says the rotor should be by now, if there is one.
+        let reference = match (self.control_mode, self.motion_profile.as_ref()) {
+            (ControlMode::Angle, Some(motion_profile)) => Some(motion_profile.sample(now)),
+            _ => None,
         };
+        if self.control_mode == ControlMode::Angle && run_angle_loop {
+            // the speed of the profile goes straight to the velocity loop, the angle loop only corrects the error.
+            let velocity_feedforward = match reference {
+                Some(reference) => {
+                    self.angle_pid.set(reference.position);
+                    reference.velocity
+                }
+                None => 0.0,
+            };
+            let velocity_target =
+                self.angle_pid.update_and_get_throttle(rads) + velocity_feedforward;
+            self.velocity_pid
+                .set(velocity_target.clamp(-self.velocity_limit, self.velocity_limit));
+        }
 
-        let mtpv_voltage_angle_rad = F32(angle_state.get_rads_per_s()
-            * self.specification.phase_inductance
-            / self.specification.phase_resistance)
-        .atan()
-        .0 + 0.1
-            * F32(
-                angle_state.get_rads_per_s() * self.specification.phase_inductance
-                    / self.specification.phase_resistance
-                    / 20.0,
-            )
-            .atan()
-            .powi(3)
-            .0;
+        // speed error gives an arbitrary unit of power that is desired to the motors.
+        // It is a q voltage or a q current depending on the torque control.
+        if let ControlMode::Angle | ControlMode::Velocity = self.control_mode {
+            if run_velocity_loop {
+                self.throttle = self.velocity_pid.update_and_get_throttle(rads_per_s);
+            }
+        }
 
-        let field_voltage = em::Vqd {
-            // q should be in the direction of the throttle.
-            q: throttle * F32(mtpv_voltage_angle_rad).cos().0,
-            // d should be 0 or negative for maximum torque per volt.
-            d: -throttle * F32(mtpv_voltage_angle_rad).sin().0,
+        let (mut throttle, torque_control) = match self.control_mode {
+            ControlMode::Angle | ControlMode::Velocity => (self.throttle, self.torque_control),
+            ControlMode::VoltageTorque => (self.target, TorqueControl::Voltage),
+            _ => (self.target, TorqueControl::Current),
         };
+        // likewise the effort to accelerate with the profile.
+        if let Some(reference) = reference {
+            throttle += self.acceleration_feedforward * reference.acceleration;
+        }
+        // the table is in the unit it was measured in, which is the motor's torque control.
+        if let Some(cogging_compensation) = self.cogging_compensation {
+            if torque_control == self.torque_control {
+                throttle += cogging_compensation.get(rads);
+            }
+        }
 
-        self.driver.set_rrf_voltage(field_voltage, electrical_angle);
+        let field_voltage = self.set_torque(
+            throttle,
+            torque_control,
+            electrical_angle,
+            rads_per_s,
+            i_abc.as_ref(),
+        );
+
+        if let (Some(angle), Some(field_voltage), Some(i_abc)) =
+            (self.angle.as_mut(), field_voltage, i_abc.as_ref())
+        {
+            angle.observe(
+                &field_voltage.inverse_parks_transformation(electrical_angle),
+                i_abc,
+            );
+        }
 
-        info!("{}, {}", self.pid.sp, angle_state.get_rads());
+        info!("{}, {}", self.target, rads);
     }
 }
@@ -0,0 +1,166 @@
+// Cogging is the rotor magnets being pulled toward the stator teeth, a torque that only depends on the angle.
+// It repeats every revolution, so it is measured once and added ahead of the loops.
+// The table holds the q effort needed to cancel it, in whatever torque control it was measured in,
+// a q voltage or a q current.
+
+use core::f32::consts;
+use micromath::F32;
+
+// N increments for one revolution of the rotor.
+// 4096 is a compromise because making it bigger feel unnecessary.
+// Big enough that it should live in a static or at the top of main, not be passed around by value.
+pub struct CoggingCompensation<const N: usize = 4096> {
+    compensation: [f32; N],
+}
+
+impl<const N: usize> CoggingCompensation<N> {
+    pub const fn new() -> Self {
+        CoggingCompensation {
+            compensation: [0.0; N],
+        }
+    }
+
+    pub fn get_table(&self) -> &[f32; N] {
+        &self.compensation
+    }
+
+    pub fn get_table_mut(&mut self) -> &mut [f32; N] {
+        &mut self.compensation
+    }
+
+    pub fn set_table(&mut self, compensation: &[f32; N]) {
+        self.compensation.copy_from_slice(compensation);
+    }
+
+    // effort to add at the rotor angle, in radians from the selected origin.
+    pub fn get(&self, rads: f32) -> f32 {
+        let revs = rads / consts::TAU;
+        let position = (revs - F32(revs).floor().0) * N as f32;
+        let index = (position as usize).min(N - 1);
+        let fraction = position - index as f32;
+        let next = (index + 1) % N;
+        self.compensation[index] * (1.0 - fraction) + self.compensation[next] * fraction
+    }
+
+    // entry where the sample at the given one of so many evenly spaced angles goes.
+    fn sample_index(sample: usize, samples: usize) -> usize {
+        sample * N / samples
+    }
+
+    // Effort measured at the given one of so many evenly spaced angles, the first at the origin.
+    pub fn set_sample(&mut self, sample: usize, samples: usize, effort: f32) {
+        self.compensation[Self::sample_index(sample, samples) % N] = effort;
+    }
+
+    pub fn get_sample(&self, sample: usize, samples: usize) -> f32 {
+        self.compensation[Self::sample_index(sample, samples) % N]
+    }
+
+    // Once all samples are set, fill in the angles between them
+    // and take out the average, since that is load or friction, not cogging.
+    pub fn fill_from_samples(&mut self, samples: usize) {
+        let samples = samples.clamp(1, N);
+        let mut sum = 0.0;
+        for sample in 0..samples {
+            let start = Self::sample_index(sample, samples);
+            let end = Self::sample_index(sample + 1, samples);
+            let from = self.compensation[start];
+            let to = self.compensation[end % N];
+            for i in start..end {
+                let fraction = (i - start) as f32 / (end - start) as f32;
+                self.compensation[i] = from + (to - from) * fraction;
+            }
+            sum += from;
+        }
+
+        let mean = sum / samples as f32;
+        for compensation in self.compensation.iter_mut() {
+            *compensation -= mean;
+        }
+    }
+
+    // Take the measurement noise out, over about `width` entries.
+    // Keep it well under N / cogging periods or the cogging goes too.
+    // An exponential filter forward then backward, which does not shift the table and needs no copy of it.
+    // A width of 0 or 1 leaves the table as it is.
+    pub fn smooth(&mut self, width: usize) {
+        if width <= 1 {
+            return;
+        }
+        let alpha = 1.0 / width as f32;
+        // run in from the other end first, the table wraps around.
+        let warm_up = (5 * width).min(N);
+
+        let mut filtered = self.compensation[N - warm_up];
+        for i in (N - warm_up)..N {
+            filtered += alpha * (self.compensation[i] - filtered);
+        }
+        for compensation in self.compensation.iter_mut() {
+            filtered += alpha * (*compensation - filtered);
+            *compensation = filtered;
+        }
+
+        let mut filtered = self.compensation[warm_up - 1];
+        for i in (0..warm_up).rev() {
+            filtered += alpha * (self.compensation[i] - filtered);
+        }
+        for compensation in self.compensation.iter_mut().rev() {
+            filtered += alpha * (*compensation - filtered);
+            *compensation = filtered;
+        }
+    }
+}
+
+impl<const N: usize> Default for CoggingCompensation<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_interpolates_and_takes_out_the_mean() {
+        let mut table = CoggingCompensation::<8>::new();
+        for (sample, effort) in [1.0, 3.0, 1.0, 3.0].into_iter().enumerate() {
+            table.set_sample(sample, 4, effort);
+        }
+        table.fill_from_samples(4);
+        assert_eq!(
+            table.get_table(),
+            &[-1.0, 0.0, 1.0, 0.0, -1.0, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(table.get(consts::TAU * 5.0 / 16.0), 0.5);
+        // wraps around both ways.
+        assert_eq!(table.get(consts::TAU * 2.0 / 8.0 - consts::TAU), 1.0);
+    }
+
+    #[test]
+    fn smooth_takes_out_noise_and_keeps_cogging() {
+        let mut table = CoggingCompensation::<1024>::new();
+        let mut compensation = [0.0; 1024];
+        for (i, compensation) in compensation.iter_mut().enumerate() {
+            let cogging = F32(i as f32 * consts::TAU * 8.0 / 1024.0).sin().0;
+            let noise = if i % 2 == 0 { 0.2 } else { -0.2 };
+            *compensation = cogging + noise;
+        }
+        table.set_table(&compensation);
+        table.smooth(4);
+        for (i, compensation) in table.get_table().iter().enumerate() {
+            let cogging = F32(i as f32 * consts::TAU * 8.0 / 1024.0).sin().0;
+            assert!((compensation - cogging).abs() < 0.05, "{i}");
+        }
+    }
+
+    #[test]
+    fn smooth_narrower_than_an_entry_does_nothing() {
+        let mut table = CoggingCompensation::<16>::new();
+        let compensation = core::array::from_fn(|i| i as f32);
+        table.set_table(&compensation);
+        table.smooth(0);
+        table.smooth(1);
+        assert_eq!(table.get_table(), &compensation);
+    }
+}
@@ -0,0 +1,57 @@
+use core::cell::Cell;
+
+// time, as micro seconds since some arbitrary start.
+pub type Instant = fugit::TimerInstantU64<1_000_000>;
+pub type Duration = fugit::TimerDurationU64<1_000_000>;
+
+// Anything that can tell the time.
+// Control logic only ever asks for the time through this, so it does not care where the time comes from.
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+// The 1MHz timer peripheral on the rp2040.
+#[cfg(feature = "rp2040")]
+impl Clock for rp2040_hal::Timer {
+    fn now(&self) -> Instant {
+        self.get_counter()
+    }
+}
+
+// A clock that only moves when told to.
+// Useful for testing and simulation where time is whatever we say it is.
+pub struct MockClock {
+    now_us: Cell<u64>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            now_us: Cell::new(0),
+        }
+    }
+
+    pub fn set(&self, instant: Instant) {
+        self.now_us.set(instant.ticks());
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now_us.set(self.now_us.get() + duration.ticks());
+    }
+
+    pub fn advance_us(&self, us: u64) {
+        self.now_us.set(self.now_us.get() + us);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        Instant::from_ticks(self.now_us.get())
+    }
+}
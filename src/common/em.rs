@@ -6,6 +6,7 @@ use micromath::F32;
 // abc
 // or stator reference frame
 // or srf
+#[derive(Clone, Copy)]
 pub struct Vabc {
     pub a: f32,
     pub b: f32,
@@ -15,22 +16,40 @@ pub struct Vabc {
 // qd
 // or rotor reference frame
 // or rrf
+#[derive(Clone, Copy)]
 pub struct Vqd {
     pub q: f32,
     pub d: f32,
 }
 
+#[derive(Clone, Copy)]
 pub struct Iabc {
     pub a: f32,
     pub b: f32,
     pub c: f32,
 }
 
+#[derive(Clone, Copy)]
 pub struct Iqd {
     pub q: f32,
     pub d: f32,
 }
 
+// alpha beta
+// or stationary two axis frame
+// it is abc squashed onto a plane, alpha lines up with a.
+#[derive(Clone, Copy)]
+pub struct Valphabeta {
+    pub alpha: f32,
+    pub beta: f32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Ialphabeta {
+    pub alpha: f32,
+    pub beta: f32,
+}
+
 impl Vabc {
     pub fn parks_transformation(&self, rotor_angle_rads: f32) -> Vqd {
         let (sa, ca) = F32(rotor_angle_rads).sin_cos();
@@ -46,16 +65,23 @@ impl Vabc {
         }
     }
 
+    pub fn clarke_transformation(&self) -> Valphabeta {
+        Valphabeta {
+            alpha: (2.0 / 3.0) * (self.a - 0.5 * self.b - 0.5 * self.c),
+            beta: (2.0 / 3.0) * 0.866_025_4 * (self.b - self.c),
+        }
+    }
+
+    // Scale the space vector down to the limit, keeping its direction.
+    // the scale is limit / magnitude.
+    // the magnitude is that of the clarke transformation, the phases are a third of a turn apart.
     pub fn limit(&self, v_limit: f32) -> Vabc {
-        let (sa, ca) = (0.0, 1.0);
-        let (sb, cb) = (-0.5, -0.866_025_4);
-        let (sc, cc) = (-0.5, 0.866_025_4);
-        let vx = (2.0 / 3.0) * (ca * self.a + cb * self.b + cc * self.c);
-        let vy = (2.0 / 3.0) * (sa * self.a + sb * self.b + sc * self.c);
-        let sqr_magnitude = vx * vx + vy * vy;
+        let v_alphabeta = self.clarke_transformation();
+        let sqr_magnitude =
+            v_alphabeta.alpha * v_alphabeta.alpha + v_alphabeta.beta * v_alphabeta.beta;
         let sqr_limit = v_limit * v_limit;
         if sqr_magnitude > sqr_limit {
-            let s = F32(sqr_magnitude / sqr_limit).sqrt().0;
+            let s = F32(sqr_limit / sqr_magnitude).sqrt().0;
             Vabc {
                 a: s * self.a,
                 b: s * self.b,
@@ -87,7 +113,7 @@ impl Vqd {
         let sqr_magnitude = self.d * self.d + self.q * self.q;
         let sqr_limit = v_limit * v_limit;
         if sqr_magnitude > sqr_limit {
-            let s = F32(sqr_magnitude / sqr_limit).sqrt().0;
+            let s = F32(sqr_limit / sqr_magnitude).sqrt().0;
             Vqd {
                 q: s * self.q,
                 d: s * self.d,
@@ -116,16 +142,20 @@ impl Iabc {
         }
     }
 
+    pub fn clarke_transformation(&self) -> Ialphabeta {
+        Ialphabeta {
+            alpha: (2.0 / 3.0) * (self.a - 0.5 * self.b - 0.5 * self.c),
+            beta: (2.0 / 3.0) * 0.866_025_4 * (self.b - self.c),
+        }
+    }
+
     pub fn limit(&self, i_limit: f32) -> Iabc {
-        let (sa, ca) = (0.0, 1.0);
-        let (sb, cb) = (-0.5, -0.866_025_4);
-        let (sc, cc) = (-0.5, 0.866_025_4);
-        let ix = (2.0 / 3.0) * (ca * self.a + cb * self.b + cc * self.c);
-        let iy = (2.0 / 3.0) * (sa * self.a + sb * self.b + sc * self.c);
-        let sqr_magnitude = ix * ix + iy * iy;
+        let i_alphabeta = self.clarke_transformation();
+        let sqr_magnitude =
+            i_alphabeta.alpha * i_alphabeta.alpha + i_alphabeta.beta * i_alphabeta.beta;
         let sqr_limit = i_limit * i_limit;
         if sqr_magnitude > sqr_limit {
-            let s = F32(sqr_magnitude / sqr_limit).sqrt().0;
+            let s = F32(sqr_limit / sqr_magnitude).sqrt().0;
             Iabc {
                 a: s * self.a,
                 b: s * self.b,
@@ -157,7 +187,7 @@ impl Iqd {
         let sqr_magnitude = self.d * self.d + self.q * self.q;
         let sqr_limit = i_limit * i_limit;
         if sqr_magnitude > sqr_limit {
-            let s = F32(sqr_magnitude / sqr_limit).sqrt().0;
+            let s = F32(sqr_limit / sqr_magnitude).sqrt().0;
             Iqd {
                 q: s * self.q,
                 d: s * self.d,
@@ -170,3 +200,47 @@ impl Iqd {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // micromath's square root is only good to a few percent, so is the limit.
+    fn is_near(value: f32, expected: f32) -> bool {
+        (value - expected).abs() <= 0.07 * expected.abs() + 1e-4
+    }
+
+    #[test]
+    fn limit_scales_down_onto_the_limit() {
+        let v = Vqd { q: 3.0, d: 4.0 }.limit(1.0);
+        assert!(is_near(v.q, 0.6) && is_near(v.d, 0.8));
+        assert!((v.q / v.d - 0.75).abs() < 1e-6);
+        let i = Iqd { q: -3.0, d: 4.0 }.limit(2.5);
+        assert!(is_near(i.q, -1.5) && is_near(i.d, 2.0));
+
+        // a space vector of magnitude 2, limited to 1.
+        let v = Vqd { q: 2.0, d: 0.0 }.inverse_parks_transformation(0.3);
+        let limited = v.limit(1.0).parks_transformation(0.3);
+        assert!(is_near(limited.q, 1.0) && limited.d.abs() < 0.01);
+        let i = Iabc {
+            a: v.a,
+            b: v.b,
+            c: v.c,
+        };
+        let limited = i.limit(1.0).parks_transformation(0.3);
+        assert!(is_near(limited.q, 1.0) && limited.d.abs() < 0.01);
+    }
+
+    #[test]
+    fn limit_leaves_smaller_vectors_alone() {
+        let v = Vqd { q: 0.3, d: -0.4 }.limit(1.0);
+        assert_eq!((v.q, v.d), (0.3, -0.4));
+        let i = Iabc {
+            a: 0.2,
+            b: -0.1,
+            c: -0.1,
+        }
+        .limit(1.0);
+        assert_eq!((i.a, i.b, i.c), (0.2, -0.1, -0.1));
+    }
+}
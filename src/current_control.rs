@@ -0,0 +1,72 @@
+use crate::bldc_motor::BLDCMotorSpecification;
+use crate::common::clock::Clock;
+use crate::common::em;
+use crate::pid::PID;
+
+// Closes the loop on the phase currents in the rotor reference frame.
+// q current makes torque, d current only heats the motor unless it is used to weaken the field.
+pub struct CurrentController<'a, T: Clock> {
+    pub pid_q: PID<'a, T>,
+    pub pid_d: PID<'a, T>,
+    // largest q current that can be asked for, amps.
+    pub current_limit: f32,
+    // target d current, 0 for maximum torque per amp, negative to weaken the field.
+    pub id_ref: f32,
+}
+
+impl<'a, T: Clock> CurrentController<'a, T> {
+    pub fn new(clock: &'a T, kp: f32, ki: f32, current_limit: f32) -> Self {
+        CurrentController {
+            pid_q: PID::new(clock, kp, ki, 0.0, 0.0),
+            pid_d: PID::new(clock, kp, ki, 0.0, 0.0),
+            current_limit,
+            id_ref: 0.0,
+        }
+    }
+
+    // Place the zero of the pi controller on the pole of the winding,
+    // which leaves a first order response with the given bandwidth in rad/s.
+    pub fn from_specification(
+        clock: &'a T,
+        specification: &BLDCMotorSpecification,
+        bandwidth: f32,
+        current_limit: f32,
+    ) -> Self {
+        Self::new(
+            clock,
+            specification.phase_inductance * bandwidth,
+            specification.phase_resistance * bandwidth,
+            current_limit,
+        )
+    }
+
+    // Work out the voltage that moves the measured currents toward the targets.
+    pub fn update(
+        &mut self,
+        iq_target: f32,
+        i_abc: &em::Iabc,
+        electrical_angle: f32,
+        voltage_limit: f32,
+    ) -> em::Vqd {
+        let i_qd = i_abc.parks_transformation(electrical_angle);
+
+        self.pid_q
+            .set(iq_target.clamp(-self.current_limit, self.current_limit));
+        self.pid_d.set(self.id_ref);
+        // each on its own can use all of the voltage, the two together are limited below.
+        self.pid_q.output_limit = voltage_limit;
+        self.pid_d.output_limit = voltage_limit;
+
+        em::Vqd {
+            q: self.pid_q.update_and_get_throttle(i_qd.q),
+            d: self.pid_d.update_and_get_throttle(i_qd.d),
+        }
+        .limit(voltage_limit)
+    }
+
+    // reset accumulated states, call when the loop has been opened.
+    pub fn reset(&mut self) {
+        self.pid_q.reset();
+        self.pid_d.reset();
+    }
+}
@@ -0,0 +1,14 @@
+#![macro_use]
+// Logging that compiles away without the defmt feature,
+// so the library can be built for a host that has no defmt logger.
+
+macro_rules! info {
+    ($s:literal $(, $x:expr)* $(,)?) => {
+        {
+            #[cfg(feature = "defmt")]
+            ::defmt::info!($s $(, $x)*);
+            #[cfg(not(feature = "defmt"))]
+            let _ = ($( & $x ),*);
+        }
+    };
+}
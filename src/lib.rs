@@ -1,6 +1,9 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
 #![allow(dead_code)]
+
+// must come first so the macros are visible to the modules below.
+mod fmt; // logging that works with or without defmt
+
 pub mod common; // shared behaviour of different modules
 
 pub mod driver; // from logic to physics
@@ -10,27 +13,50 @@ pub mod bldc_motor; // implement foc for bldc.
 
 pub mod pid; // logic for pid
 
+pub mod current_control; // logic for the d/q current loops
+
+pub mod autotune; // finding pid gains by relay feedback
+
+pub mod cogging; // feedforward for the torque ripple of the magnets
+
+pub mod motion; // trajectories for getting from one angle to another
+
+pub mod storage; // keeping calibration and tuning in flash
+
+#[cfg(any(test, feature = "sim"))]
+pub mod sim; // simulated motor for closed loop testing without hardware
+
+// What the motor is asked to do, and so what the target means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlMode {
+    // target is the rotor angle in radians.
+    Angle,
+    // target is the rotor speed in radians per second.
+    Velocity,
+    // target is the q voltage in volts.
+    VoltageTorque,
+    // target is the q current in amps.
+    CurrentTorque,
+    // target is the rotor speed in radians per second, the sensor is not looked at.
+    // the only modes that work without a sensor.
+    OpenLoopVelocity,
+    // target is the rotor angle in radians, the sensor is not looked at.
+    // the rotor is moved there at up to the velocity limit.
+    OpenLoopAngle,
+}
+
 // The control interface to 3 phase motors.
 // Once initialized, the internal components can be hidden away.
 pub trait FOCMotor {
+    fn set_control_mode(&mut self, mode: ControlMode);
+    fn get_control_mode(&self) -> ControlMode;
+    // the unit depends on the control mode.
+    fn set_target(&mut self, target: f32);
     fn goto(&mut self, target: f32);
     fn goto_blocking(&mut self, target: f32);
     fn foc_loop(&mut self);
 }
 
-// TODO: cogging torque compensation
-// pub struct CoggingCompensation {
-//     // 4096 increments for one revolution of the rotor.
-//     // a compromise because making it bigger feel unnecessary.
-//     compensation_voltage: [f32; 4096],
-// }
-
-// impl CoggingCompensation {
-//     fn test() -() {
-
-//     }
-// }
-
 //
 // https://drive.google.com/file/d/13rkv5P4SPrwZmB9B4wkn2wWThXyDSLwa/view?usp=sharing
 
@@ -37,8 +37,10 @@ use micromath::F32;
 
 // made drivers
 use foc_port::driver::{self, BLDCDriver};
+use foc_port::motion;
 use foc_port::pid;
 use foc_port::sensor::{self, RotarySensor, RotorState};
+use foc_port::storage;
 use foc_port::FOCMotor;
 use foc_port::{bldc_motor, sensor::magnetic_i2c};
 
@@ -117,6 +119,7 @@ fn main() -> ! {
     channel1a.output_to(pins.gpio18);
 
     let mut motor = bldc_motor::BLDCMotor::new(
+        // placeholders, only measurable with `motor.identify_parameters()` once current sensing is fitted.
         bldc_motor::BLDCMotorSpecification {
             pole_pairs: 7,
             kv: 1000,
@@ -133,16 +136,28 @@ fn main() -> ! {
             b: channel0b,
             c: channel1a,
         },
-        pid::PID::new(&timer, 10.0, 100.0, 0.1, 0.0),
+        // angle error to speed, then speed error to voltage.
+        pid::PID::new(&timer, 20.0, 0.0, 0.0, 0.0),
+        pid::PID::new(&timer, 0.5, 10.0, 0.0, 0.0),
     );
 
-    motor
-        .angle
-        .as_mut()
-        .unwrap()
-        .set_return_mapping(true, 0.455);
-
-    // motor.calibrate_rotary_sensor();
+    // the calibration is kept in the last 64 KB of the pico's 2 MB flash, well clear of the program.
+    // only the first power up calibrates, run `storage::save` again after tuning to keep the gains.
+    let mut flash = storage::Rp2040Flash::<{ 2 * 1024 * 1024 }>::new(pac.XIP_SSI);
+    let calibration = match motor.load_config_or_calibrate(&mut flash, 0x1f_0000, None) {
+        Ok(calibration) => calibration,
+        Err(_) => {
+            info!("flash could not be read, calibrating without saving");
+            motor.calibrate_rotary_sensor()
+        }
+    };
+    if calibration.is_none() {
+        defmt::panic!("calibration failed, check the motor supply and the sensor");
+    }
+    // 50 revolutions each way, ramped up to the velocity limit and back down instead of all at once.
+    motor.motion_profile = Some(motion::MotionProfile::Trapezoidal(
+        motion::TrapezoidalProfile::new(motor.velocity_limit, 100.0),
+    ));
 
     info!("Open Loop Testing");
     loop {
@@ -0,0 +1,726 @@
+// Trajectories for moving the rotor between positions.
+// Jumping the set point of the angle loop asks for everything at once, so a long move saturates
+// the loops and overshoots. A profile moves the set point instead, no faster than the motor can follow,
+// and gives the speed it is moving at so the velocity loop does not have to wait for an error.
+
+use micromath::F32;
+
+use crate::common::clock::Instant;
+
+// Where a trajectory is at some instant.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionSetpoint {
+    // rad
+    pub position: f32,
+    // rad/s
+    pub velocity: f32,
+    // rad/s^2
+    pub acceleration: f32,
+}
+
+// Accelerate, cruise, decelerate, with the speed against time looking like a trapezoid.
+// Moves that are too short to reach the cruise speed make a triangle instead.
+// Planned from wherever it is when a new target comes, so it can be retargeted in the middle of a move.
+pub struct TrapezoidalProfile {
+    // rad/s
+    pub max_velocity: f32,
+    // rad/s^2
+    pub max_acceleration: f32,
+
+    // where the plan starts from.
+    start: MotionSetpoint,
+    start_time: Instant,
+    target: f32,
+    // of the move, the plan is worked out as if it was positive.
+    direction: f32,
+    // acceleration toward the cruise speed, negative if it starts too fast.
+    start_acceleration: f32,
+    cruise_velocity: f32,
+    // distance and time of the accelerate and cruise phases, time of the decelerate phase.
+    start_distance: f32,
+    cruise_distance: f32,
+    start_time_s: f32,
+    cruise_time_s: f32,
+    stop_time_s: f32,
+}
+
+impl TrapezoidalProfile {
+    pub fn new(max_velocity: f32, max_acceleration: f32) -> Self {
+        let mut profile = TrapezoidalProfile {
+            max_velocity,
+            max_acceleration,
+            start: MotionSetpoint {
+                position: 0.0,
+                velocity: 0.0,
+                acceleration: 0.0,
+            },
+            start_time: Instant::from_ticks(0),
+            target: 0.0,
+            direction: 1.0,
+            start_acceleration: 0.0,
+            cruise_velocity: 0.0,
+            start_distance: 0.0,
+            cruise_distance: 0.0,
+            start_time_s: 0.0,
+            cruise_time_s: 0.0,
+            stop_time_s: 0.0,
+        };
+        profile.reset(0.0, Instant::from_ticks(0));
+        profile
+    }
+
+    // Stand still at the position, with nothing left to do.
+    pub fn reset(&mut self, position: f32, now: Instant) {
+        self.start = MotionSetpoint {
+            position,
+            velocity: 0.0,
+            acceleration: 0.0,
+        };
+        self.start_time = now;
+        self.target = position;
+        self.plan();
+    }
+
+    // Head for the target from wherever the profile is now, at whatever speed.
+    pub fn set_target(&mut self, target: f32, now: Instant) {
+        self.start = self.sample(now);
+        self.start_time = now;
+        self.target = target;
+        self.plan();
+    }
+
+    pub fn get_target(&self) -> f32 {
+        self.target
+    }
+
+    // seconds from the last target to the end of the move.
+    pub fn get_duration(&self) -> f32 {
+        self.start_time_s + self.cruise_time_s + self.stop_time_s
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        seconds_since(self.start_time, now) >= self.get_duration()
+    }
+
+    pub fn sample(&self, now: Instant) -> MotionSetpoint {
+        let t = seconds_since(self.start_time, now);
+        let a = self.max_acceleration;
+        let v0 = self.direction * self.start.velocity;
+        let vc = self.cruise_velocity;
+
+        // distance, speed and acceleration, all in the direction of the move.
+        let (distance, velocity, acceleration) = if t < self.start_time_s {
+            let a1 = self.start_acceleration;
+            (v0 * t + a1 * t * t / 2.0, v0 + a1 * t, a1)
+        } else if t < self.start_time_s + self.cruise_time_s {
+            let t = t - self.start_time_s;
+            (self.start_distance + vc * t, vc, 0.0)
+        } else if t < self.get_duration() {
+            let t = t - self.start_time_s - self.cruise_time_s;
+            (
+                self.start_distance + self.cruise_distance + vc * t - a * t * t / 2.0,
+                vc - a * t,
+                -a,
+            )
+        } else {
+            return MotionSetpoint {
+                position: self.target,
+                velocity: 0.0,
+                acceleration: 0.0,
+            };
+        };
+
+        MotionSetpoint {
+            position: self.start.position + self.direction * distance,
+            velocity: self.direction * velocity,
+            acceleration: self.direction * acceleration,
+        }
+    }
+
+    fn plan(&mut self) {
+        let a = self.max_acceleration.max(f32::MIN_POSITIVE);
+        let v0 = self.start.velocity;
+
+        // head for the target from where the profile could stop, so a move the other way
+        // first comes to a stop and then turns around.
+        let stopping_position = self.start.position + v0 * F32(v0).abs().0 / (2.0 * a);
+        self.direction = if self.target >= stopping_position {
+            1.0
+        } else {
+            -1.0
+        };
+        let v0 = self.direction * v0;
+        let distance = self.direction * (self.target - self.start.position);
+
+        // as fast as allowed, or as fast as there is room to slow down from.
+        let peak_velocity = sqrt((2.0 * a * distance + v0 * v0) / 2.0);
+        let vc = self.max_velocity.max(0.0).min(peak_velocity);
+        self.cruise_velocity = vc;
+        self.start_acceleration = if v0 > vc { -a } else { a };
+
+        self.start_time_s = F32(vc - v0).abs().0 / a;
+        self.start_distance = (vc * vc - v0 * v0) / (2.0 * self.start_acceleration);
+        self.stop_time_s = vc / a;
+        let stop_distance = vc * vc / (2.0 * a);
+        self.cruise_distance = (distance - self.start_distance - stop_distance).max(0.0);
+        self.cruise_time_s = if vc > 0.0 {
+            self.cruise_distance / vc
+        } else {
+            0.0
+        };
+    }
+}
+
+// Seven segments, the acceleration itself ramps up and down at a limited jerk.
+// Slower than a trapezoid with the same limits, but the force on whatever is carried never steps,
+// which is what shakes a camera or a slider.
+// Retargeting in the middle of a move starts again from the position and speed there,
+// the acceleration does step at that moment.
+pub struct SCurveProfile {
+    // rad/s
+    pub max_velocity: f32,
+    // rad/s^2
+    pub max_acceleration: f32,
+    // rad/s^3
+    pub max_jerk: f32,
+
+    start: MotionSetpoint,
+    start_time: Instant,
+    target: f32,
+    // of the move, the plan is worked out as if it was positive.
+    direction: f32,
+    // from the starting speed to the cruise speed, then from the cruise speed to a stop.
+    to_cruise: VelocityRamp,
+    to_stop: VelocityRamp,
+    cruise_distance: f32,
+    cruise_time_s: f32,
+}
+
+impl SCurveProfile {
+    // steps of the search for the cruise speed of a move too short to reach the max.
+    const SEARCH_STEPS: u8 = 32;
+
+    pub fn new(max_velocity: f32, max_acceleration: f32, max_jerk: f32) -> Self {
+        let mut profile = SCurveProfile {
+            max_velocity,
+            max_acceleration,
+            max_jerk,
+            start: MotionSetpoint {
+                position: 0.0,
+                velocity: 0.0,
+                acceleration: 0.0,
+            },
+            start_time: Instant::from_ticks(0),
+            target: 0.0,
+            direction: 1.0,
+            to_cruise: VelocityRamp::default(),
+            to_stop: VelocityRamp::default(),
+            cruise_distance: 0.0,
+            cruise_time_s: 0.0,
+        };
+        profile.reset(0.0, Instant::from_ticks(0));
+        profile
+    }
+
+    // Stand still at the position, with nothing left to do.
+    pub fn reset(&mut self, position: f32, now: Instant) {
+        self.start = MotionSetpoint {
+            position,
+            velocity: 0.0,
+            acceleration: 0.0,
+        };
+        self.start_time = now;
+        self.target = position;
+        self.plan();
+    }
+
+    // Head for the target from wherever the profile is now, at whatever speed.
+    pub fn set_target(&mut self, target: f32, now: Instant) {
+        self.start = self.sample(now);
+        self.start_time = now;
+        self.target = target;
+        self.plan();
+    }
+
+    pub fn get_target(&self) -> f32 {
+        self.target
+    }
+
+    // seconds from the last target to the end of the move.
+    pub fn get_duration(&self) -> f32 {
+        self.to_cruise.duration() + self.cruise_time_s + self.to_stop.duration()
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        seconds_since(self.start_time, now) >= self.get_duration()
+    }
+
+    pub fn sample(&self, now: Instant) -> MotionSetpoint {
+        let t = seconds_since(self.start_time, now);
+        let to_cruise_time_s = self.to_cruise.duration();
+
+        // distance, speed and acceleration, all in the direction of the move.
+        let (distance, velocity, acceleration) = if t < to_cruise_time_s {
+            self.to_cruise.sample(t)
+        } else if t < to_cruise_time_s + self.cruise_time_s {
+            let t = t - to_cruise_time_s;
+            (
+                self.to_cruise.distance() + self.to_cruise.end_velocity * t,
+                self.to_cruise.end_velocity,
+                0.0,
+            )
+        } else if t < self.get_duration() {
+            let t = t - to_cruise_time_s - self.cruise_time_s;
+            let (distance, velocity, acceleration) = self.to_stop.sample(t);
+            (
+                self.to_cruise.distance() + self.cruise_distance + distance,
+                velocity,
+                acceleration,
+            )
+        } else {
+            return MotionSetpoint {
+                position: self.target,
+                velocity: 0.0,
+                acceleration: 0.0,
+            };
+        };
+
+        MotionSetpoint {
+            position: self.start.position + self.direction * distance,
+            velocity: self.direction * velocity,
+            acceleration: self.direction * acceleration,
+        }
+    }
+
+    fn ramp(&self, from: f32, to: f32) -> VelocityRamp {
+        VelocityRamp::new(from, to, self.max_acceleration, self.max_jerk)
+    }
+
+    fn plan(&mut self) {
+        let v0 = self.start.velocity;
+
+        // head for the target from where the profile could stop, so a move the other way
+        // first comes to a stop and then turns around.
+        let stopping_distance = self.ramp(F32(v0).abs().0, 0.0).distance();
+        let stopping_position = self.start.position + stopping_distance * v0.signum();
+        self.direction = if self.target >= stopping_position {
+            1.0
+        } else {
+            -1.0
+        };
+        let v0 = self.direction * v0;
+        let distance = self.direction * (self.target - self.start.position);
+
+        // the distance taken to get to a cruise speed and back to a stop grows with the cruise speed,
+        // so a move too short for the max is found by halving the interval.
+        let distance_at = |vc: f32| self.ramp(v0, vc).distance() + self.ramp(vc, 0.0).distance();
+        let max_velocity = self.max_velocity.max(0.0);
+        let vc = if v0 >= max_velocity || distance_at(max_velocity) <= distance {
+            max_velocity
+        } else {
+            let mut low = v0.max(0.0);
+            let mut high = max_velocity;
+            for _ in 0..Self::SEARCH_STEPS {
+                let middle = (low + high) / 2.0;
+                if distance_at(middle) <= distance {
+                    low = middle;
+                } else {
+                    high = middle;
+                }
+            }
+            low
+        };
+
+        self.to_cruise = self.ramp(v0, vc);
+        self.to_stop = self.ramp(vc, 0.0);
+        self.cruise_distance =
+            (distance - self.to_cruise.distance() - self.to_stop.distance()).max(0.0);
+        self.cruise_time_s = if vc > 0.0 {
+            self.cruise_distance / vc
+        } else {
+            0.0
+        };
+    }
+}
+
+// A change of speed at limited acceleration and jerk.
+// The jerk ramps the acceleration up, it holds if there is time, then the jerk ramps it back down.
+#[derive(Default)]
+struct VelocityRamp {
+    start_velocity: f32,
+    end_velocity: f32,
+    // 1 to speed up, -1 to slow down.
+    sign: f32,
+    jerk: f32,
+    peak_acceleration: f32,
+    // time the acceleration ramps for, each way, and holds for.
+    jerk_time_s: f32,
+    hold_time_s: f32,
+}
+
+impl VelocityRamp {
+    fn new(from: f32, to: f32, max_acceleration: f32, max_jerk: f32) -> Self {
+        let jerk = max_jerk.max(f32::MIN_POSITIVE);
+        let max_acceleration = max_acceleration.max(f32::MIN_POSITIVE);
+        let change = F32(to - from).abs().0;
+        // a change too small to reach the max acceleration ramps straight up and back down.
+        let (peak_acceleration, hold_time_s) =
+            if change * jerk >= max_acceleration * max_acceleration {
+                (
+                    max_acceleration,
+                    change / max_acceleration - max_acceleration / jerk,
+                )
+            } else {
+                (sqrt(change * jerk), 0.0)
+            };
+        VelocityRamp {
+            start_velocity: from,
+            end_velocity: to,
+            sign: if to >= from { 1.0 } else { -1.0 },
+            jerk,
+            peak_acceleration,
+            jerk_time_s: peak_acceleration / jerk,
+            hold_time_s,
+        }
+    }
+
+    fn duration(&self) -> f32 {
+        2.0 * self.jerk_time_s + self.hold_time_s
+    }
+
+    // the acceleration is symmetric, so the average speed is halfway.
+    fn distance(&self) -> f32 {
+        (self.start_velocity + self.end_velocity) / 2.0 * self.duration()
+    }
+
+    // distance, speed and acceleration, t seconds in.
+    fn sample(&self, t: f32) -> (f32, f32, f32) {
+        let (s, j, a) = (self.sign, self.jerk, self.peak_acceleration);
+        let tj = self.jerk_time_s;
+        let v0 = self.start_velocity;
+        if t < tj {
+            return (
+                v0 * t + s * j * t * t * t / 6.0,
+                v0 + s * j * t * t / 2.0,
+                s * j * t,
+            );
+        }
+
+        let x1 = v0 * tj + s * j * tj * tj * tj / 6.0;
+        let v1 = v0 + s * j * tj * tj / 2.0;
+        if t < tj + self.hold_time_s {
+            let t = t - tj;
+            return (x1 + v1 * t + s * a * t * t / 2.0, v1 + s * a * t, s * a);
+        }
+
+        let th = self.hold_time_s;
+        let x2 = x1 + v1 * th + s * a * th * th / 2.0;
+        let v2 = v1 + s * a * th;
+        if t < self.duration() {
+            let t = t - tj - th;
+            return (
+                x2 + v2 * t + s * (a * t * t / 2.0 - j * t * t * t / 6.0),
+                v2 + s * (a * t - j * t * t / 2.0),
+                s * (a - j * t),
+            );
+        }
+        (self.distance(), self.end_velocity, 0.0)
+    }
+}
+
+// micromath's square root is only good to a fraction of a percent, which is enough to leave a step
+// where the segments of a profile meet. A couple of newton steps take it to full precision.
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    let mut root = F32(x).sqrt().0;
+    for _ in 0..2 {
+        root = (root + x / root) / 2.0;
+    }
+    root
+}
+
+fn seconds_since(start: Instant, now: Instant) -> f32 {
+    now.checked_duration_since(start)
+        .map_or(0.0, |duration| duration.to_micros() as f32 / 1_000_000.0)
+}
+
+// The profiles the motor can follow, see `BLDCMotor::motion_profile`.
+pub enum MotionProfile {
+    Trapezoidal(TrapezoidalProfile),
+    SCurve(SCurveProfile),
+}
+
+impl MotionProfile {
+    pub fn reset(&mut self, position: f32, now: Instant) {
+        match self {
+            MotionProfile::Trapezoidal(profile) => profile.reset(position, now),
+            MotionProfile::SCurve(profile) => profile.reset(position, now),
+        }
+    }
+
+    pub fn set_target(&mut self, target: f32, now: Instant) {
+        match self {
+            MotionProfile::Trapezoidal(profile) => profile.set_target(target, now),
+            MotionProfile::SCurve(profile) => profile.set_target(target, now),
+        }
+    }
+
+    pub fn get_target(&self) -> f32 {
+        match self {
+            MotionProfile::Trapezoidal(profile) => profile.get_target(),
+            MotionProfile::SCurve(profile) => profile.get_target(),
+        }
+    }
+
+    pub fn get_duration(&self) -> f32 {
+        match self {
+            MotionProfile::Trapezoidal(profile) => profile.get_duration(),
+            MotionProfile::SCurve(profile) => profile.get_duration(),
+        }
+    }
+
+    pub fn is_done(&self, now: Instant) -> bool {
+        match self {
+            MotionProfile::Trapezoidal(profile) => profile.is_done(now),
+            MotionProfile::SCurve(profile) => profile.is_done(now),
+        }
+    }
+
+    pub fn sample(&self, now: Instant) -> MotionSetpoint {
+        match self {
+            MotionProfile::Trapezoidal(profile) => profile.sample(now),
+            MotionProfile::SCurve(profile) => profile.sample(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP_US: u64 = 100;
+
+    fn at(us: u64) -> Instant {
+        Instant::from_ticks(us)
+    }
+
+    // Walk the profile a step at a time and check that it goes nowhere without the speed to,
+    // and stays within the limits. Gives back the largest speed and acceleration seen.
+    fn check(
+        sample: impl Fn(Instant) -> MotionSetpoint,
+        from_us: u64,
+        to_us: u64,
+        max_velocity: f32,
+        max_acceleration: f32,
+    ) -> (f32, f32) {
+        let dt = STEP_US as f32 / 1_000_000.0;
+        let mut prior = sample(at(from_us));
+        let (mut peak_velocity, mut peak_acceleration) = (0.0f32, 0.0f32);
+        for us in (from_us + STEP_US..=to_us).step_by(STEP_US as usize) {
+            let setpoint = sample(at(us));
+            let moved = setpoint.position - prior.position;
+            let expected = (prior.velocity + setpoint.velocity) / 2.0 * dt;
+            assert!(
+                // a few of the last bits of a position around 100.
+                (moved - expected).abs() < max_acceleration * dt * dt + 5e-5,
+                "{us} us: moved {moved}, at the speed {expected}"
+            );
+            let change = (setpoint.velocity - prior.velocity).abs();
+            assert!(
+                change <= max_acceleration * dt * 1.01 + 1e-5,
+                "{us} us: speed changed {change}"
+            );
+            peak_velocity = peak_velocity.max(setpoint.velocity.abs());
+            peak_acceleration = peak_acceleration.max(setpoint.acceleration.abs());
+            prior = setpoint;
+        }
+        assert!(peak_velocity <= max_velocity * 1.001);
+        assert!(peak_acceleration <= max_acceleration * 1.001);
+        (peak_velocity, peak_acceleration)
+    }
+
+    // the acceleration never steps, it moves no faster than the jerk allows.
+    fn check_jerk(
+        sample: impl Fn(Instant) -> MotionSetpoint,
+        from_us: u64,
+        to_us: u64,
+        max_jerk: f32,
+    ) {
+        let dt = STEP_US as f32 / 1_000_000.0;
+        let mut prior = sample(at(from_us));
+        for us in (from_us + STEP_US..=to_us).step_by(STEP_US as usize) {
+            let setpoint = sample(at(us));
+            let change = (setpoint.acceleration - prior.acceleration).abs();
+            assert!(
+                change <= max_jerk * dt * 1.01 + 1e-3,
+                "{us} us: acceleration changed {change}"
+            );
+            prior = setpoint;
+        }
+    }
+
+    fn is_at_rest(setpoint: MotionSetpoint, position: f32) -> bool {
+        setpoint
+            == MotionSetpoint {
+                position,
+                velocity: 0.0,
+                acceleration: 0.0,
+            }
+    }
+
+    #[test]
+    fn trapezoid() {
+        let mut profile = TrapezoidalProfile::new(20.0, 100.0);
+        assert!(profile.is_done(at(0)));
+        profile.set_target(100.0, at(0));
+        // 0.2 s up to speed, 4.8 s at it, 0.2 s down.
+        assert!((profile.get_duration() - 5.2).abs() < 1e-4);
+        let end_us = (profile.get_duration() * 1_000_000.0) as u64;
+        let (peak_velocity, _) = check(|now| profile.sample(now), 0, end_us + 1000, 20.0, 100.0);
+        assert!((peak_velocity - 20.0).abs() < 1e-4);
+        assert!(!profile.is_done(at(end_us - 1000)));
+        assert!(profile.is_done(at(end_us + 1000)));
+        assert!(is_at_rest(profile.sample(at(end_us + 1000)), 100.0));
+
+        // and back the other way.
+        profile.set_target(-100.0, at(end_us + 1000));
+        let start_us = end_us + 1000;
+        let end_us = start_us + (profile.get_duration() * 1_000_000.0) as u64;
+        check(
+            |now| profile.sample(now),
+            start_us,
+            end_us + 1000,
+            20.0,
+            100.0,
+        );
+        assert!(is_at_rest(profile.sample(at(end_us + 1000)), -100.0));
+    }
+
+    #[test]
+    fn trapezoid_too_short_for_the_max_speed() {
+        let mut profile = TrapezoidalProfile::new(20.0, 100.0);
+        profile.set_target(1.0, at(0));
+        // a triangle, half a radian each way at 100 rad/s^2.
+        assert!((profile.get_duration() - 0.2).abs() < 1e-4);
+        let (peak_velocity, _) = check(|now| profile.sample(now), 0, 210_000, 20.0, 100.0);
+        assert!((peak_velocity - 10.0).abs() < 0.02);
+        assert!(is_at_rest(profile.sample(at(210_000)), 1.0));
+    }
+
+    #[test]
+    fn trapezoid_retarget_with_reversal() {
+        let mut profile = TrapezoidalProfile::new(20.0, 100.0);
+        profile.set_target(100.0, at(0));
+        // a second in, cruising forward.
+        let setpoint = profile.sample(at(1_000_000));
+        assert!((setpoint.position - 18.0).abs() < 1e-3);
+        assert_eq!(setpoint.velocity, 20.0);
+
+        // behind it, so it stops, which takes it to 20, and comes back.
+        profile.set_target(-10.0, at(1_000_000));
+        let turning = profile.sample(at(1_000_000));
+        assert_eq!(
+            (turning.position, turning.velocity),
+            (setpoint.position, setpoint.velocity)
+        );
+        assert_eq!(turning.acceleration, -100.0);
+        let end_us = 1_000_000 + (profile.get_duration() * 1_000_000.0) as u64;
+        check(
+            |now| profile.sample(now),
+            1_000_000,
+            end_us + 1000,
+            20.0,
+            100.0,
+        );
+        let furthest = (1_000_000..end_us)
+            .step_by(STEP_US as usize)
+            .map(|us| profile.sample(at(us)).position)
+            .fold(f32::MIN, f32::max);
+        assert!((furthest - 20.0).abs() < 1e-3);
+        assert!(is_at_rest(profile.sample(at(end_us + 1000)), -10.0));
+
+        // just short of where it would stop, it overshoots and comes back too.
+        let mut profile = TrapezoidalProfile::new(20.0, 100.0);
+        profile.set_target(100.0, at(0));
+        profile.set_target(19.0, at(1_000_000));
+        let end_us = 1_000_000 + (profile.get_duration() * 1_000_000.0) as u64;
+        check(
+            |now| profile.sample(now),
+            1_000_000,
+            end_us + 1000,
+            20.0,
+            100.0,
+        );
+        assert!(is_at_rest(profile.sample(at(end_us + 1000)), 19.0));
+    }
+
+    #[test]
+    fn s_curve() {
+        let mut profile = SCurveProfile::new(20.0, 100.0, 1000.0);
+        assert!(profile.is_done(at(0)));
+        profile.set_target(100.0, at(0));
+        // 0.1 s of jerk each way and 0.1 s at the max acceleration, to speed and back, 4.7 s cruising.
+        assert!((profile.get_duration() - 5.3).abs() < 1e-4);
+        let end_us = (profile.get_duration() * 1_000_000.0) as u64;
+        let sample = |now| profile.sample(now);
+        let (peak_velocity, peak_acceleration) = check(sample, 0, end_us + 1000, 20.0, 100.0);
+        check_jerk(sample, 0, end_us + 1000, 1000.0);
+        assert!((peak_velocity - 20.0).abs() < 1e-4);
+        assert!((peak_acceleration - 100.0).abs() < 1e-3);
+        assert!(profile.is_done(at(end_us + 1000)));
+        assert!(is_at_rest(profile.sample(at(end_us + 1000)), 100.0));
+    }
+
+    #[test]
+    fn s_curve_too_short_for_the_max_speed() {
+        // 2 rad reaches the max acceleration but not the max speed,
+        // 0.05 rad not even the max acceleration, the speed is found by the search.
+        for (target, max_acceleration) in [(2.0, true), (-2.0, true), (0.05, false)] {
+            let mut profile = SCurveProfile::new(20.0, 100.0, 1000.0);
+            profile.set_target(target, at(0));
+            let end_us = (profile.get_duration() * 1_000_000.0) as u64;
+            let sample = |now| profile.sample(now);
+            let (peak_velocity, peak_acceleration) = check(sample, 0, end_us + 1000, 20.0, 100.0);
+            check_jerk(sample, 0, end_us + 1000, 1000.0);
+            assert!(peak_velocity < 20.0, "{target}");
+            assert_eq!(
+                (peak_acceleration - 100.0).abs() < 1e-3,
+                max_acceleration,
+                "{target}"
+            );
+            // it never goes past the target on the way.
+            for us in (0..end_us).step_by(STEP_US as usize) {
+                let position = profile.sample(at(us)).position;
+                assert!(position.abs() <= target.abs() + 1e-6, "{target} at {us} us");
+            }
+            assert!(is_at_rest(profile.sample(at(end_us + 1000)), target));
+        }
+    }
+
+    #[test]
+    fn s_curve_retarget_with_reversal() {
+        let mut profile = SCurveProfile::new(20.0, 100.0, 1000.0);
+        profile.set_target(100.0, at(0));
+        // a second in, cruising forward at 17 rad.
+        let setpoint = profile.sample(at(1_000_000));
+        assert!((setpoint.position - 17.0).abs() < 1e-3);
+        assert_eq!(setpoint.velocity, 20.0);
+
+        // it stops and comes back. it turns a little short of the 20 a full stop would take,
+        // since the braking runs straight on into speeding up the other way.
+        profile.set_target(-10.0, at(1_000_000));
+        assert_eq!(profile.sample(at(1_000_000)), setpoint);
+        let end_us = 1_000_000 + (profile.get_duration() * 1_000_000.0) as u64;
+        let sample = |now| profile.sample(now);
+        check(sample, 1_000_000, end_us + 1000, 20.0, 100.0);
+        check_jerk(sample, 1_000_000, end_us + 1000, 1000.0);
+        let furthest = (1_000_000..end_us)
+            .step_by(STEP_US as usize)
+            .map(|us| profile.sample(at(us)).position)
+            .fold(f32::MIN, f32::max);
+        assert!(furthest > 19.9 && furthest <= 20.0);
+        assert!(is_at_rest(profile.sample(at(end_us + 1000)), -10.0));
+    }
+}
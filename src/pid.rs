@@ -1,61 +1,258 @@
-use rp2040_hal::Timer;
+use crate::common::clock::{Clock, Instant};
 
-pub struct PID<'a> {
-    pub timer: &'a Timer,
+// The tuning of a PID on its own, to keep or move between controllers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PIDGains {
+    pub kp: f32,
+    pub ki: f32,
+    pub kd: f32,
+}
+
+// What the integral does while the output is held at its limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AntiWindup {
+    // stop integrating while the error would push the output further past the limit.
+    Clamping,
+    // bleed the integral by how far past the limit the output is, times this gain.
+    // around 1 / sqrt(Ti Td), or ki / kp for a PI.
+    BackCalculation(f32),
+}
+
+pub struct PID<'a, T: Clock> {
+    pub clock: &'a T,
     pub kp: f32,
     pub ki: f32,
     pub kd: f32,
     pub sp: f32,
+    // the output is kept within plus or minus this.
+    pub output_limit: f32,
+    pub anti_windup: AntiWindup,
+    // the integral term is kept within plus or minus this, in the units of the output.
+    pub integral_limit: f32,
+    // seconds, time constant of the low pass on the derivative, 0 for none.
+    pub derivative_filter: f32,
+    // fraction of the set point the proportional term sees, below 1 softens the kick of a new set point.
+    // the integral always sees all of it, so it still gets there.
+    pub setpoint_weight: f32,
     is_new: bool,
-    prior_time: rp2040_hal::fugit::Instant<u64, 1, 1000000>,
-    prior_error: f32,
-    sum: f32,
+    prior_time: Instant,
+    prior_value: f32,
+    // in the units of the output, so changing ki does not make it jump.
+    integral: f32,
+    // filtered rate of change of the measurement.
+    derivative: f32,
 }
-impl<'a> PID<'a> {
+impl<'a, T: Clock> PID<'a, T> {
     // constructor
-    pub fn new(timer: &'a Timer, kp: f32, ki: f32, kd: f32, sp: f32) -> PID {
+    // without limits, filtering or weighting, set the fields for those.
+    pub fn new(clock: &'a T, kp: f32, ki: f32, kd: f32, sp: f32) -> Self {
         PID {
-            timer,
+            clock,
             kp,
             ki,
             kd,
             sp,
+            output_limit: f32::INFINITY,
+            anti_windup: AntiWindup::Clamping,
+            integral_limit: f32::INFINITY,
+            derivative_filter: 0.0,
+            setpoint_weight: 1.0,
 
             is_new: true,
-            prior_time: timer.get_counter(),
-            prior_error: 0.0,
-            sum: 0.0,
+            prior_time: clock.now(),
+            prior_value: 0.0,
+            integral: 0.0,
+            derivative: 0.0,
         }
     }
 
+    pub fn get_gains(&self) -> PIDGains {
+        PIDGains {
+            kp: self.kp,
+            ki: self.ki,
+            kd: self.kd,
+        }
+    }
+
+    // Change the gains without the output jumping, the integral takes up the difference.
+    pub fn set_gains(&mut self, gains: &PIDGains) {
+        if !self.is_new {
+            let proportional = self.setpoint_weight * self.sp - self.prior_value;
+            self.integral +=
+                (self.kp - gains.kp) * proportional - (self.kd - gains.kd) * self.derivative;
+        }
+        self.kp = gains.kp;
+        self.ki = gains.ki;
+        self.kd = gains.kd;
+    }
+
     // set a set point
+    // the integral is kept, and the derivative is of the measurement, so the output does not kick.
     pub fn set(&mut self, sp: f32) {
-        self.reset();
         self.sp = sp;
     }
 
     // takes in a reading and give out a value.
     pub fn update_and_get_throttle(&mut self, value: f32) -> f32 {
-        let now = self.timer.get_counter();
+        let now = self.clock.now();
         let dt = (now - self.prior_time).to_micros() as f32 / 1_000_000.0;
+        self.prior_time = now;
         let error = self.sp - value;
 
-        self.sum += error * dt;
-        let derror = if !self.is_new {
-            (error - self.prior_error) / dt
-        } else {
-            0.0
-        };
+        // nothing to take a rate of change over on the first reading, or on a clock that has not moved.
+        if !self.is_new && dt > 0.0 {
+            let rate = (value - self.prior_value) / dt;
+            self.derivative += (rate - self.derivative) * dt / (self.derivative_filter + dt);
+        }
+        let proportional = self.kp * (self.setpoint_weight * self.sp - value);
+        let derivative = -self.kd * self.derivative;
+
+        if !self.is_new && dt > 0.0 {
+            let unlimited = proportional + self.integral + self.ki * error * dt + derivative;
+            let limited = unlimited.clamp(-self.output_limit, self.output_limit);
+            match self.anti_windup {
+                AntiWindup::Clamping => {
+                    // integrating back away from the limit is always fine.
+                    if unlimited == limited || error * unlimited < 0.0 {
+                        self.integral += self.ki * error * dt;
+                    }
+                }
+                AntiWindup::BackCalculation(gain) => {
+                    self.integral += (self.ki * error + gain * (limited - unlimited)) * dt;
+                }
+            }
+            self.integral = self
+                .integral
+                .clamp(-self.integral_limit, self.integral_limit);
+        }
         self.is_new = false;
-        self.prior_error = error;
-        self.prior_time = now;
-        self.kp * (error) + self.ki * (self.sum) + self.kd * (derror)
+        self.prior_value = value;
+
+        (proportional + self.integral + derivative).clamp(-self.output_limit, self.output_limit)
     }
 
     // reset accumulated states
     pub fn reset(&mut self) {
         self.is_new = true;
-        self.prior_error = 0.0;
-        self.sum = 0.0;
+        self.prior_value = 0.0;
+        self.integral = 0.0;
+        self.derivative = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::clock::MockClock;
+
+    // 10 ms on, then a reading.
+    fn step(pid: &mut PID<'_, MockClock>, clock: &MockClock, value: f32) -> f32 {
+        clock.advance_us(10_000);
+        pid.update_and_get_throttle(value)
+    }
+
+    #[test]
+    fn clamping_stops_the_integral_at_the_limit() {
+        let clock = MockClock::new();
+        let mut pid = PID::new(&clock, 1.0, 10.0, 0.0, 10.0);
+        pid.output_limit = 1.0;
+        for _ in 0..100 {
+            assert_eq!(step(&mut pid, &clock, 0.0), 1.0);
+        }
+        assert_eq!(pid.integral, 0.0);
+
+        // so it comes off the limit as soon as the error is small enough.
+        pid.set(0.5);
+        assert!((step(&mut pid, &clock, 0.0) - 0.55).abs() < 1e-4);
+    }
+
+    #[test]
+    fn back_calculation_bleeds_the_integral() {
+        let clock = MockClock::new();
+        let mut pid = PID::new(&clock, 1.0, 10.0, 0.0, 10.0);
+        pid.output_limit = 1.0;
+        pid.anti_windup = AntiWindup::BackCalculation(5.0);
+        for _ in 0..1000 {
+            assert_eq!(step(&mut pid, &clock, 0.0), 1.0);
+        }
+        // it stops growing where the bleed matches the error, ki e = gain (unlimited - limit),
+        // 10 * 10 = 5 * (10 + integral + 10 * 10 * 0.01 - 1).
+        assert!((pid.integral - 10.0).abs() < 1e-3, "{}", pid.integral);
+    }
+
+    #[test]
+    fn integral_limit() {
+        let clock = MockClock::new();
+        let mut pid = PID::new(&clock, 0.0, 1.0, 0.0, 1.0);
+        pid.integral_limit = 0.5;
+        let mut output = 0.0;
+        for _ in 0..200 {
+            output = step(&mut pid, &clock, 0.0);
+        }
+        assert_eq!(output, 0.5);
+        let mut pid = PID::new(&clock, 0.0, 1.0, 0.0, -1.0);
+        pid.integral_limit = 0.5;
+        for _ in 0..200 {
+            output = step(&mut pid, &clock, 0.0);
+        }
+        assert_eq!(output, -0.5);
+    }
+
+    #[test]
+    fn derivative_is_of_the_measurement() {
+        let clock = MockClock::new();
+        let mut pid = PID::new(&clock, 0.0, 0.0, 1.0, 0.0);
+        assert_eq!(step(&mut pid, &clock, 0.0), 0.0);
+        // a new set point does not kick.
+        pid.set(5.0);
+        assert_eq!(step(&mut pid, &clock, 0.0), 0.0);
+        // the measurement moving does, against the way it moves.
+        assert!((step(&mut pid, &clock, 0.1) + 10.0).abs() < 1e-3);
+        assert_eq!(step(&mut pid, &clock, 0.1), 0.0);
+    }
+
+    #[test]
+    fn derivative_filter() {
+        let clock = MockClock::new();
+        let mut pid = PID::new(&clock, 0.0, 0.0, 1.0, 0.0);
+        // a time constant of 9 steps, so a tenth of the change comes through at once.
+        pid.derivative_filter = 0.09;
+        step(&mut pid, &clock, 0.0);
+        assert!((step(&mut pid, &clock, 0.1) + 1.0).abs() < 1e-3);
+        // and the rest follows.
+        let mut output = 0.0;
+        for _ in 0..100 {
+            output = step(&mut pid, &clock, 0.1);
+        }
+        assert!(output.abs() < 1e-3);
+    }
+
+    #[test]
+    fn set_gains_is_bumpless() {
+        let clock = MockClock::new();
+        let mut pid = PID::new(&clock, 1.0, 1.0, 0.1, 1.0);
+        let mut value = 0.0;
+        let mut output = 0.0;
+        for _ in 0..50 {
+            output = step(&mut pid, &clock, value);
+            value += 0.005;
+        }
+        pid.set_gains(&PIDGains {
+            kp: 3.0,
+            ki: 2.0,
+            kd: 0.5,
+        });
+        assert_eq!(
+            pid.get_gains(),
+            PIDGains {
+                kp: 3.0,
+                ki: 2.0,
+                kd: 0.5
+            }
+        );
+        // hardly any time for anything to move, so the output should not either.
+        clock.advance_us(100);
+        let value = value - 0.005 + 0.005 / 100.0;
+        assert!((pid.update_and_get_throttle(value) - output).abs() < 1e-3);
     }
 }
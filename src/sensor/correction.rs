@@ -0,0 +1,219 @@
+// Magnets are never quite centred on the shaft or over the sensor, so the reading is off
+// by an amount that repeats every revolution, mostly once and twice per turn.
+// A straight line fit can not take that out, so what is left over is kept in a table by reading
+// and taken off every reading before it is used.
+
+use core::f32::consts;
+
+// entries over one revolution, plenty for errors a few harmonics deep.
+pub const SENSOR_CORRECTION_SIZE: usize = 128;
+// entries averaged together when making the table.
+const SMOOTHING_WIDTH: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SensorCorrection {
+    // error of the reading at each evenly spaced reading, in counts of a 16 bit reading.
+    table: [i16; SENSOR_CORRECTION_SIZE],
+}
+
+impl SensorCorrection {
+    pub fn from_table(table: [i16; SENSOR_CORRECTION_SIZE]) -> Self {
+        SensorCorrection { table }
+    }
+
+    pub fn get_table(&self) -> &[i16; SENSOR_CORRECTION_SIZE] {
+        &self.table
+    }
+
+    // error at the reading, interpolated between entries.
+    pub fn get(&self, reading: u16) -> f32 {
+        let position = reading as f32 * SENSOR_CORRECTION_SIZE as f32 / 65536.0;
+        let index = (position as usize).min(SENSOR_CORRECTION_SIZE - 1);
+        let fraction = position - index as f32;
+        let next = (index + 1) % SENSOR_CORRECTION_SIZE;
+        self.table[index] as f32 * (1.0 - fraction) + self.table[next] as f32 * fraction
+    }
+
+    pub fn apply(&self, reading: u16) -> u16 {
+        let error = self.get(reading);
+        // round to the nearest count, either way.
+        let error = if error < 0.0 {
+            error - 0.5
+        } else {
+            error + 0.5
+        } as i32;
+        (reading as i32 - error) as u16
+    }
+}
+
+// Collects the residuals of a calibration by reading, the line they are residuals of is only known at the end.
+pub struct SensorResiduals {
+    // sums of the commanded and measured angles, and how many there were, by reading.
+    commanded: [f32; SENSOR_CORRECTION_SIZE],
+    measured: [f32; SENSOR_CORRECTION_SIZE],
+    count: [u16; SENSOR_CORRECTION_SIZE],
+}
+
+impl SensorResiduals {
+    pub fn new() -> Self {
+        SensorResiduals {
+            commanded: [0.0; SENSOR_CORRECTION_SIZE],
+            measured: [0.0; SENSOR_CORRECTION_SIZE],
+            count: [0; SENSOR_CORRECTION_SIZE],
+        }
+    }
+
+    // the raw reading and the commanded and measured angles at it.
+    pub fn add(&mut self, reading: u16, commanded: f32, measured: f32) {
+        // nearest entry, so each entry averages the readings around it.
+        let index = ((reading as u32 * SENSOR_CORRECTION_SIZE as u32 + 32768) >> 16) as usize
+            % SENSOR_CORRECTION_SIZE;
+        self.commanded[index] += commanded;
+        self.measured[index] += measured;
+        self.count[index] = self.count[index].saturating_add(1);
+    }
+
+    // Turn the residuals from the line measured = slope * commanded + intercept into a correction.
+    // The measured angle is the reading mapped by the rotor state, `is_correct_direction` undoes the sign.
+    // Gives nothing if less than half the revolution was seen.
+    pub fn to_correction(
+        &self,
+        slope: f32,
+        intercept: f32,
+        is_correct_direction: bool,
+    ) -> Option<SensorCorrection> {
+        let seen = self.count.iter().filter(|count| **count > 0).count();
+        if 2 * seen < SENSOR_CORRECTION_SIZE {
+            return None;
+        }
+
+        let sign = if is_correct_direction { 1.0 } else { -1.0 };
+        let mut residuals = [0.0; SENSOR_CORRECTION_SIZE];
+        for (i, residual) in residuals.iter_mut().enumerate() {
+            if self.count[i] > 0 {
+                let n = self.count[i] as f32;
+                let rads = self.measured[i] / n - slope * self.commanded[i] / n - intercept;
+                *residual = sign * rads * 65536.0 / consts::TAU;
+            }
+        }
+
+        // fill the gaps in a straight line between the entries either side.
+        for i in 0..SENSOR_CORRECTION_SIZE {
+            if self.count[i] > 0 {
+                continue;
+            }
+            let mut before = 1;
+            while self.count[(i + SENSOR_CORRECTION_SIZE - before) % SENSOR_CORRECTION_SIZE] == 0 {
+                before += 1;
+            }
+            let mut after = 1;
+            while self.count[(i + after) % SENSOR_CORRECTION_SIZE] == 0 {
+                after += 1;
+            }
+            let from = residuals[(i + SENSOR_CORRECTION_SIZE - before) % SENSOR_CORRECTION_SIZE];
+            let to = residuals[(i + after) % SENSOR_CORRECTION_SIZE];
+            residuals[i] = from + (to - from) * before as f32 / (before + after) as f32;
+        }
+
+        // the rotor is pulled off the commanded angle by cogging, which comes a few times per entry
+        // and is nothing to do with the sensor, average it out over neighbouring entries.
+        let mut smoothed = [0.0; SENSOR_CORRECTION_SIZE];
+        for (i, smooth) in smoothed.iter_mut().enumerate() {
+            *smooth = (0..SMOOTHING_WIDTH)
+                .map(|j| {
+                    residuals[(i + SENSOR_CORRECTION_SIZE + j - SMOOTHING_WIDTH / 2)
+                        % SENSOR_CORRECTION_SIZE]
+                })
+                .sum::<f32>()
+                / SMOOTHING_WIDTH as f32;
+        }
+
+        // an offset is for the origin to take care of, not the table.
+        let mean = smoothed.iter().sum::<f32>() / SENSOR_CORRECTION_SIZE as f32;
+        let mut table = [0i16; SENSOR_CORRECTION_SIZE];
+        for (entry, smooth) in table.iter_mut().zip(smoothed.iter()) {
+            *entry = (smooth - mean).clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+        Some(SensorCorrection { table })
+    }
+}
+
+impl Default for SensorResiduals {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use micromath::F32;
+
+    // error of a reading, in counts, once and twice per revolution.
+    fn error(reading: u16) -> f32 {
+        let rads = reading as f32 * consts::TAU / 65536.0;
+        200.0 * F32(rads).sin().0 + 50.0 * F32(2.0 * rads).cos().0
+    }
+
+    #[test]
+    fn table_goes_out_and_back_unchanged() {
+        let table = core::array::from_fn(|i| (i as i16 - 64) * 3);
+        let correction = SensorCorrection::from_table(table);
+        assert_eq!(correction.get_table(), &table);
+        assert_eq!(
+            SensorCorrection::from_table(*correction.get_table()),
+            correction
+        );
+    }
+
+    #[test]
+    fn get_interpolates_and_apply_takes_it_off() {
+        let mut table = [0; SENSOR_CORRECTION_SIZE];
+        table[1] = 100;
+        table[2] = 200;
+        let correction = SensorCorrection::from_table(table);
+        let entry = (65536 / SENSOR_CORRECTION_SIZE) as u16;
+        assert_eq!(correction.get(entry), 100.0);
+        assert_eq!(correction.get(entry + entry / 2), 150.0);
+        assert_eq!(correction.apply(entry + entry / 2), entry + entry / 2 - 150);
+        // around the end of the table back to the start.
+        table[0] = -100;
+        let correction = SensorCorrection::from_table(table);
+        assert_eq!(correction.get(u16::MAX - entry / 2 + 1), -50.0);
+        assert_eq!(correction.apply(0), 100);
+    }
+
+    #[test]
+    fn residuals_become_the_correction() {
+        // readings of a sensor turning forward with the error on top, along the line 2 x + 0.1.
+        let mut residuals = SensorResiduals::new();
+        for i in 0..1024 {
+            let rads = i as f32 * consts::TAU / 1024.0;
+            let true_reading = (rads * 65536.0 / consts::TAU) as u16;
+            let reading = (true_reading as f32 + error(true_reading)) as u16;
+            let measured = reading as f32 * consts::TAU / 65536.0;
+            residuals.add(reading, (rads - 0.1) / 2.0, measured);
+        }
+        let correction = residuals.to_correction(2.0, 0.1, true).unwrap();
+
+        let mut worst: f32 = 0.0;
+        for i in 0..256 {
+            let true_reading = (i * 256) as u16;
+            let reading = (true_reading as f32 + error(true_reading)) as u16;
+            let left = correction.apply(reading) as i16 - true_reading as i16;
+            worst = worst.max(F32(left as f32).abs().0);
+        }
+        // out of 250, the smoothing takes a little off the peaks.
+        assert!(worst < 20.0, "{worst}");
+    }
+
+    #[test]
+    fn residuals_of_half_a_revolution_are_not_enough() {
+        let mut residuals = SensorResiduals::new();
+        for i in 0..SENSOR_CORRECTION_SIZE / 2 - 1 {
+            let reading = (i * 65536 / SENSOR_CORRECTION_SIZE) as u16;
+            residuals.add(reading, 0.0, 0.0);
+        }
+        assert_eq!(residuals.to_correction(1.0, 0.0, true), None);
+    }
+}
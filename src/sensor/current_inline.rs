@@ -0,0 +1,326 @@
+// Inline current sensing, a shunt resistor in series with each phase followed by an amplifier into an adc.
+// The phase current always flows through the shunt, so unlike low side sensing it can be sampled at any time.
+
+use micromath::F32;
+
+use super::{CurrentSenseError, CurrentSensor};
+use crate::common::em;
+use crate::driver::BLDCDriver;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    A,
+    B,
+    C,
+}
+
+// Whatever turns the amplifier outputs into numbers.
+// An adc is usually shared between channels, so one reader serves all the phases.
+pub trait PhaseAdc {
+    fn read(&mut self, phase: Phase) -> Result<u16, CurrentSenseError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShuntLayout {
+    // every phase is measured.
+    Three,
+    // the currents sum to 0, so the last phase is worked out from the other two.
+    Two { unmeasured: Phase },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InlineCurrentSenseConfig {
+    // ohm
+    pub shunt_resistance: f32,
+    // volt per volt, negative if the amplifier inverts.
+    pub amplifier_gain: f32,
+    // volt at full scale of the adc.
+    pub adc_reference_voltage: f32,
+    pub adc_bit_resolution: u8,
+    pub shunts: ShuntLayout,
+}
+
+// A common setup, 10 mOhm shunts into an INA240A2 read by the rp2040 adc.
+pub const INA240A2_10MOHM_CONFIG: InlineCurrentSenseConfig = InlineCurrentSenseConfig {
+    shunt_resistance: 0.01,
+    amplifier_gain: 50.0,
+    adc_reference_voltage: 3.3,
+    adc_bit_resolution: 12,
+    shunts: ShuntLayout::Three,
+};
+
+pub struct InlineCurrentSensor<A: PhaseAdc> {
+    adc: A,
+    config: InlineCurrentSenseConfig,
+    // adc counts when no current flows, index by phase.
+    offsets: [f32; 3],
+}
+
+impl<A: PhaseAdc> InlineCurrentSensor<A> {
+    // number of readings averaged when calibrating.
+    const OFFSET_SAMPLES: u16 = 1000;
+    // fraction of full scale that the offset may be away from half scale.
+    const OFFSET_TOLERANCE: f32 = 0.1;
+
+    pub fn new(adc: A, config: InlineCurrentSenseConfig) -> Self {
+        // bidirectional amplifiers sit at half scale when there is no current.
+        let half_scale = Self::full_scale(&config) / 2.0;
+        Self {
+            adc,
+            config,
+            offsets: [half_scale; 3],
+        }
+    }
+
+    pub fn release(self) -> A {
+        self.adc
+    }
+
+    pub fn get_offsets(&self) -> [f32; 3] {
+        self.offsets
+    }
+
+    // Measure the reading at zero current.
+    // This turns the driver off, so call it at startup before the motor is used.
+    pub fn calibrate_offsets<B: BLDCDriver>(
+        &mut self,
+        driver: &mut B,
+    ) -> Result<(), CurrentSenseError> {
+        driver.off();
+
+        let mut sums = [0.0f32; 3];
+        for _ in 0..Self::OFFSET_SAMPLES {
+            for phase in self.measured_phases() {
+                sums[phase as usize] += self.adc.read(phase)? as f32;
+            }
+        }
+
+        let full_scale = Self::full_scale(&self.config);
+        let mut offsets = self.offsets;
+        for phase in self.measured_phases() {
+            let offset = sums[phase as usize] / Self::OFFSET_SAMPLES as f32;
+            if F32(offset - full_scale / 2.0).abs().0 > Self::OFFSET_TOLERANCE * full_scale {
+                return Err(CurrentSenseError::OffsetOutOfRange);
+            }
+            offsets[phase as usize] = offset;
+        }
+        self.offsets = offsets;
+        Ok(())
+    }
+
+    fn full_scale(config: &InlineCurrentSenseConfig) -> f32 {
+        ((1u32 << config.adc_bit_resolution) - 1) as f32
+    }
+
+    fn amps_per_count(&self) -> f32 {
+        self.config.adc_reference_voltage
+            / Self::full_scale(&self.config)
+            / (self.config.amplifier_gain * self.config.shunt_resistance)
+    }
+
+    fn measured_phases(&self) -> impl Iterator<Item = Phase> {
+        let unmeasured = match self.config.shunts {
+            ShuntLayout::Three => None,
+            ShuntLayout::Two { unmeasured } => Some(unmeasured),
+        };
+        [Phase::A, Phase::B, Phase::C]
+            .into_iter()
+            .filter(move |phase| Some(*phase) != unmeasured)
+    }
+
+    fn read_amps(&mut self, phase: Phase) -> Result<f32, CurrentSenseError> {
+        let counts = self.adc.read(phase)? as f32;
+        Ok((counts - self.offsets[phase as usize]) * self.amps_per_count())
+    }
+}
+
+impl<A: PhaseAdc> CurrentSensor for InlineCurrentSensor<A> {
+    fn get_phase_currents(&mut self) -> Result<em::Iabc, CurrentSenseError> {
+        match self.config.shunts {
+            ShuntLayout::Three => Ok(em::Iabc {
+                a: self.read_amps(Phase::A)?,
+                b: self.read_amps(Phase::B)?,
+                c: self.read_amps(Phase::C)?,
+            }),
+            // kirchhoff, whatever goes in must come out.
+            ShuntLayout::Two {
+                unmeasured: Phase::A,
+            } => {
+                let b = self.read_amps(Phase::B)?;
+                let c = self.read_amps(Phase::C)?;
+                Ok(em::Iabc { a: -b - c, b, c })
+            }
+            ShuntLayout::Two {
+                unmeasured: Phase::B,
+            } => {
+                let a = self.read_amps(Phase::A)?;
+                let c = self.read_amps(Phase::C)?;
+                Ok(em::Iabc { a, b: -a - c, c })
+            }
+            ShuntLayout::Two {
+                unmeasured: Phase::C,
+            } => {
+                let a = self.read_amps(Phase::A)?;
+                let b = self.read_amps(Phase::B)?;
+                Ok(em::Iabc { a, b, c: -a - b })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the same counts every read, and an error from any phase that should not be read.
+    struct MockAdc {
+        counts: [u16; 3],
+        unreadable: Option<Phase>,
+    }
+
+    impl PhaseAdc for MockAdc {
+        fn read(&mut self, phase: Phase) -> Result<u16, CurrentSenseError> {
+            if Some(phase) == self.unreadable {
+                return Err(CurrentSenseError::Adc);
+            }
+            Ok(self.counts[phase as usize])
+        }
+    }
+
+    struct MockDriver {
+        is_on: bool,
+    }
+
+    impl BLDCDriver for MockDriver {
+        fn get_voltage_limit(&self) -> f32 {
+            12.0
+        }
+
+        fn set_srf_voltage(&mut self, _v_srf: em::Vabc) {
+            self.is_on = true;
+        }
+
+        fn set_rrf_voltage(&mut self, _v_rrf: em::Vqd, _rotor_angle: f32) {
+            self.is_on = true;
+        }
+
+        fn off(&mut self) {
+            self.is_on = false;
+        }
+    }
+
+    fn new_sensor(
+        config: InlineCurrentSenseConfig,
+        counts: [u16; 3],
+    ) -> InlineCurrentSensor<MockAdc> {
+        let unreadable = match config.shunts {
+            ShuntLayout::Three => None,
+            ShuntLayout::Two { unmeasured } => Some(unmeasured),
+        };
+        InlineCurrentSensor::new(MockAdc { counts, unreadable }, config)
+    }
+
+    fn assert_amps(currents: em::Iabc, a: f32, b: f32, c: f32) {
+        for (amps, expected) in [(currents.a, a), (currents.b, b), (currents.c, c)] {
+            // within a count or two.
+            assert!((amps - expected).abs() < 5e-3, "{amps} for {expected}");
+        }
+    }
+
+    // counts of 1 A through 10 mOhm at a gain of 50 into 3.3 V over 12 bits, from half scale.
+    const AMP: f32 = 0.5 * 4095.0 / 3.3;
+
+    fn counts(amps: f32) -> u16 {
+        (2047.5 + amps * AMP + 0.5) as u16
+    }
+
+    #[test]
+    fn scaled_by_shunt_and_gain() {
+        let readings = [counts(1.0), counts(-2.0), counts(1.0)];
+        let currents = new_sensor(INA240A2_10MOHM_CONFIG, readings)
+            .get_phase_currents()
+            .unwrap();
+        assert_amps(currents, 1.0, -2.0, 1.0);
+
+        // half the shunt is twice the current for the same counts.
+        let config = InlineCurrentSenseConfig {
+            shunt_resistance: 0.005,
+            ..INA240A2_10MOHM_CONFIG
+        };
+        let currents = new_sensor(config, readings).get_phase_currents().unwrap();
+        assert_amps(currents, 2.0, -4.0, 2.0);
+
+        // and an inverting amplifier the other way round.
+        let config = InlineCurrentSenseConfig {
+            amplifier_gain: -50.0,
+            ..INA240A2_10MOHM_CONFIG
+        };
+        let currents = new_sensor(config, readings).get_phase_currents().unwrap();
+        assert_amps(currents, -1.0, 2.0, -1.0);
+    }
+
+    #[test]
+    fn sensor_by_kirchhoff() {
+        // the unmeasured phase reads nonsense, if it is read at all.
+        let readings = [counts(1.5), counts(-0.5), counts(-1.0)];
+        for unmeasured in [Phase::A, Phase::B, Phase::C] {
+            let config = InlineCurrentSenseConfig {
+                shunts: ShuntLayout::Two { unmeasured },
+                ..INA240A2_10MOHM_CONFIG
+            };
+            let mut readings = readings;
+            readings[unmeasured as usize] = 0;
+            let currents = new_sensor(config, readings).get_phase_currents().unwrap();
+            assert_amps(currents, 1.5, -0.5, -1.0);
+        }
+    }
+
+    #[test]
+    fn adc_errors_come_through() {
+        let mut sensor = new_sensor(INA240A2_10MOHM_CONFIG, [2048; 3]);
+        sensor.adc.unreadable = Some(Phase::B);
+        assert!(matches!(
+            sensor.get_phase_currents(),
+            Err(CurrentSenseError::Adc)
+        ));
+        let mut driver = MockDriver { is_on: true };
+        assert_eq!(
+            sensor.calibrate_offsets(&mut driver),
+            Err(CurrentSenseError::Adc)
+        );
+    }
+
+    #[test]
+    fn offsets_are_calibrated_with_the_driver_off() {
+        let mut sensor = new_sensor(INA240A2_10MOHM_CONFIG, [2100, 2000, 2060]);
+        let mut driver = MockDriver { is_on: true };
+        sensor.calibrate_offsets(&mut driver).unwrap();
+        assert!(!driver.is_on);
+        assert_eq!(sensor.get_offsets(), [2100.0, 2000.0, 2060.0]);
+        assert_amps(sensor.get_phase_currents().unwrap(), 0.0, 0.0, 0.0);
+
+        // the phase that is not measured keeps half scale.
+        let config = InlineCurrentSenseConfig {
+            shunts: ShuntLayout::Two {
+                unmeasured: Phase::C,
+            },
+            ..INA240A2_10MOHM_CONFIG
+        };
+        let mut sensor = new_sensor(config, [2100, 2000, 0]);
+        sensor.calibrate_offsets(&mut driver).unwrap();
+        assert_eq!(sensor.get_offsets(), [2100.0, 2000.0, 2047.5]);
+    }
+
+    #[test]
+    fn offset_far_from_half_scale_is_refused() {
+        // over a tenth of full scale off, as if the driver were still pushing current.
+        let mut sensor = new_sensor(INA240A2_10MOHM_CONFIG, [2048, 2048, 2048 + 420]);
+        let mut driver = MockDriver { is_on: true };
+        assert_eq!(
+            sensor.calibrate_offsets(&mut driver),
+            Err(CurrentSenseError::OffsetOutOfRange)
+        );
+        // none of it is taken.
+        assert_eq!(sensor.get_offsets(), [2047.5; 3]);
+    }
+}
@@ -0,0 +1,382 @@
+// Quadrature encoders, optical or magnetic, with two channels a quarter cycle apart and an optional index.
+// The channels go 00, 01, 11, 10 when turning forward, so every step changes exactly one of them.
+// The pins are polled, so poll at least once per step, the fastest edges being about cpr * 4 * revs per second.
+
+use super::SensorError;
+use embedded_hal::digital::{ErrorType, InputPin};
+
+use super::RotarySensor;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoding {
+    // one count per cycle, on the rising edge of a.
+    X1,
+    // two counts per cycle, on both edges of a.
+    X2,
+    // four counts per cycle, on every edge.
+    X4,
+}
+
+impl Decoding {
+    pub fn counts_per_cycle(&self) -> u32 {
+        match self {
+            Decoding::X1 => 1,
+            Decoding::X2 => 2,
+            Decoding::X4 => 4,
+        }
+    }
+}
+
+pub struct EncoderConfig {
+    // cycles per revolution, the number of lines on the disc.
+    pub cpr: u32,
+    pub decoding: Decoding,
+}
+
+// Stands in for the index pin of an encoder that does not have one, it can never be made.
+pub enum NoIndexPin {}
+
+impl ErrorType for NoIndexPin {
+    type Error = core::convert::Infallible;
+}
+
+impl InputPin for NoIndexPin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        match *self {}
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        match *self {}
+    }
+}
+
+pub struct Encoder<A: InputPin, B: InputPin, I: InputPin = NoIndexPin> {
+    a: A,
+    b: B,
+    index: Option<I>,
+    config: EncoderConfig,
+
+    // last state of the channels, a as the high bit.
+    state: u8,
+    prior_index: bool,
+
+    // counts since power up, in the chosen decoding.
+    count: i32,
+    // count at which the index was last seen.
+    index_count: Option<i32>,
+
+    // times both channels changed between polls, so the direction is unknown.
+    illegal_transitions: u32,
+    // times the index came at a different count than the revolution before.
+    index_misses: u32,
+}
+
+impl<A: InputPin, B: InputPin, I: InputPin> Encoder<A, B, I> {
+    pub fn new(mut a: A, mut b: B, index: Option<I>, config: EncoderConfig) -> Self {
+        // a bad first reading only shifts where zero is, which is arbitrary before the index anyway.
+        let state =
+            ((a.is_high().unwrap_or(false) as u8) << 1) | b.is_high().unwrap_or(false) as u8;
+        Encoder {
+            a,
+            b,
+            index,
+            config,
+
+            state,
+            prior_index: true,
+
+            count: 0,
+            index_count: None,
+
+            illegal_transitions: 0,
+            index_misses: 0,
+        }
+    }
+
+    pub fn release(self) -> (A, B, Option<I>) {
+        (self.a, self.b, self.index)
+    }
+
+    pub fn get_config(&self) -> &EncoderConfig {
+        &self.config
+    }
+
+    pub fn counts_per_rev(&self) -> u32 {
+        self.config.cpr * self.config.decoding.counts_per_cycle()
+    }
+
+    pub fn get_count(&self) -> i32 {
+        self.count
+    }
+
+    // Once the index is found the angle is absolute, so an alignment found once holds after every power up.
+    // The angle jumps when it is found, so turn past it before the encoder goes into a RotorState.
+    pub fn is_index_found(&self) -> bool {
+        self.index_count.is_some()
+    }
+
+    pub fn get_illegal_transitions(&self) -> u32 {
+        self.illegal_transitions
+    }
+
+    pub fn get_index_misses(&self) -> u32 {
+        self.index_misses
+    }
+
+    // Read the pins and count the step since the last poll.
+    pub fn poll(&mut self) -> Result<(), SensorError> {
+        let a = self.a.is_high().map_err(|_| SensorError::Bus)?;
+        let b = self.b.is_high().map_err(|_| SensorError::Bus)?;
+        let state = ((a as u8) << 1) | b as u8;
+
+        let prior_state = self.state;
+        self.state = state;
+        // position of each state along the forward sequence 00, 01, 11, 10.
+        let position = |state: u8| state ^ (state >> 1);
+        let step = match (position(state) + 4 - position(prior_state)) % 4 {
+            0 => Some(0),
+            1 => Some(1),
+            3 => Some(-1),
+            // both channels changed, a step was missed.
+            _ => None,
+        };
+
+        if let Some(step) = step {
+            let a_changed = (state ^ prior_state) & 0b10 != 0;
+            let counts = match self.config.decoding {
+                Decoding::X4 => step != 0,
+                Decoding::X2 => a_changed,
+                // rising going forward is falling going back, so back and forth over the edge cancels out.
+                Decoding::X1 => a_changed && (a == (step > 0)),
+            };
+            if counts {
+                self.count += step;
+            }
+        }
+
+        // even after a missed step, an index edge missed now would not come again for a turn.
+        if let Some(index) = self.index.as_mut() {
+            let is_index = index.is_high().map_err(|_| SensorError::Bus)?;
+            if is_index && !self.prior_index {
+                self.on_index();
+            }
+            self.prior_index = is_index;
+        }
+
+        match step {
+            Some(_) => Ok(()),
+            None => {
+                self.illegal_transitions += 1;
+                Err(SensorError::InvalidState)
+            }
+        }
+    }
+
+    fn on_index(&mut self) {
+        let counts_per_rev = self.counts_per_rev() as i32;
+        if let Some(index_count) = self.index_count {
+            // the index comes at the same place every turn, from either side it is a step or so apart.
+            let drift = (self.count - index_count).rem_euclid(counts_per_rev);
+            let drift = drift.min(counts_per_rev - drift);
+            if drift <= self.config.decoding.counts_per_cycle() as i32 {
+                return;
+            }
+            self.index_misses += 1;
+        }
+        self.index_count = Some(self.count);
+    }
+}
+
+impl<A: InputPin, B: InputPin, I: InputPin> RotarySensor for Encoder<A, B, I> {
+    fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
+        self.poll()?;
+        let counts_per_rev = self.counts_per_rev().max(1);
+        let position =
+            (self.count - self.index_count.unwrap_or(0)).rem_euclid(counts_per_rev as i32);
+        Ok(((position as u64 * 65536) / counts_per_rev as u64) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    // a pin that reads whatever the test last set it to.
+    struct MockPin<'a>(&'a Cell<bool>);
+
+    impl ErrorType for MockPin<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl InputPin for MockPin<'_> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.0.get())
+        }
+    }
+
+    struct Pins {
+        a: Cell<bool>,
+        b: Cell<bool>,
+        index: Cell<bool>,
+    }
+
+    impl Pins {
+        fn new() -> Self {
+            Pins {
+                a: Cell::new(false),
+                b: Cell::new(false),
+                index: Cell::new(false),
+            }
+        }
+
+        fn encoder(&self, cpr: u32, decoding: Decoding) -> Encoder<MockPin<'_>, MockPin<'_>> {
+            Encoder::new(
+                MockPin(&self.a),
+                MockPin(&self.b),
+                None,
+                EncoderConfig { cpr, decoding },
+            )
+        }
+
+        fn encoder_with_index(
+            &self,
+            cpr: u32,
+            decoding: Decoding,
+        ) -> Encoder<MockPin<'_>, MockPin<'_>, MockPin<'_>> {
+            Encoder::new(
+                MockPin(&self.a),
+                MockPin(&self.b),
+                Some(MockPin(&self.index)),
+                EncoderConfig { cpr, decoding },
+            )
+        }
+
+        // one step along 00, 01, 11, 10 from wherever the channels are, forward or back.
+        fn step<I: InputPin>(
+            &self,
+            encoder: &mut Encoder<MockPin<'_>, MockPin<'_>, I>,
+            forward: bool,
+        ) -> Result<(), SensorError> {
+            // forward, b follows a when they differ and leads it when they are the same.
+            if (self.a.get() == self.b.get()) == forward {
+                self.b.set(!self.b.get());
+            } else {
+                self.a.set(!self.a.get());
+            }
+            encoder.poll()
+        }
+
+        fn steps<I: InputPin>(
+            &self,
+            encoder: &mut Encoder<MockPin<'_>, MockPin<'_>, I>,
+            steps: i32,
+        ) {
+            for _ in 0..steps.abs() {
+                self.step(encoder, steps > 0).unwrap();
+            }
+        }
+
+        fn pulse_index<I: InputPin>(&self, encoder: &mut Encoder<MockPin<'_>, MockPin<'_>, I>) {
+            self.index.set(true);
+            encoder.poll().unwrap();
+            self.index.set(false);
+            encoder.poll().unwrap();
+        }
+    }
+
+    #[test]
+    fn counts_by_decoding() {
+        for (decoding, counts) in [(Decoding::X1, 1), (Decoding::X2, 2), (Decoding::X4, 4)] {
+            let pins = Pins::new();
+            let mut encoder = pins.encoder(100, decoding);
+            // three cycles forward, one back.
+            pins.steps(&mut encoder, 12);
+            assert_eq!(encoder.get_count(), 3 * counts, "{decoding:?}");
+            pins.steps(&mut encoder, -4);
+            assert_eq!(encoder.get_count(), 2 * counts, "{decoding:?}");
+            assert_eq!(encoder.counts_per_rev(), 100 * counts as u32);
+        }
+    }
+
+    #[test]
+    fn back_and_forth_over_an_edge_cancels() {
+        for decoding in [Decoding::X1, Decoding::X2, Decoding::X4] {
+            let pins = Pins::new();
+            let mut encoder = pins.encoder(100, decoding);
+            // up to just before the rising edge of a, then over it and back, many times.
+            pins.steps(&mut encoder, 1);
+            let count = encoder.get_count();
+            for _ in 0..10 {
+                pins.steps(&mut encoder, 1);
+                pins.steps(&mut encoder, -1);
+            }
+            assert_eq!(encoder.get_count(), count, "{decoding:?}");
+        }
+    }
+
+    #[test]
+    fn illegal_transition_is_reported() {
+        let pins = Pins::new();
+        let mut encoder = pins.encoder(100, Decoding::X4);
+        pins.steps(&mut encoder, 1);
+        // 01 to 10, both channels at once.
+        pins.a.set(true);
+        pins.b.set(false);
+        assert_eq!(encoder.poll(), Err(SensorError::InvalidState));
+        assert_eq!(encoder.get_illegal_transitions(), 1);
+        assert_eq!(encoder.get_count(), 1);
+        // and it carries on from the new state.
+        pins.steps(&mut encoder, -1);
+        assert_eq!(encoder.get_count(), 0);
+    }
+
+    #[test]
+    fn index_is_not_lost_with_an_illegal_transition() {
+        let pins = Pins::new();
+        let mut encoder = pins.encoder_with_index(4, Decoding::X4);
+        pins.steps(&mut encoder, 5);
+        // 01 to 10 as the index comes.
+        pins.a.set(true);
+        pins.b.set(false);
+        pins.index.set(true);
+        assert_eq!(encoder.poll(), Err(SensorError::InvalidState));
+        assert!(encoder.is_index_found());
+        assert_eq!(encoder.get_mechanical_angle(), Ok(0));
+        // and the same pulse is not taken again.
+        pins.steps(&mut encoder, 1);
+        pins.index.set(false);
+        pins.steps(&mut encoder, 1);
+        assert_eq!(encoder.get_index_misses(), 0);
+        assert_eq!(encoder.get_mechanical_angle(), Ok(8192));
+    }
+
+    #[test]
+    fn index_sets_zero_and_resyncs() {
+        let pins = Pins::new();
+        let mut encoder = pins.encoder_with_index(4, Decoding::X4);
+        pins.steps(&mut encoder, 3);
+        assert!(!encoder.is_index_found());
+        pins.pulse_index(&mut encoder);
+        assert!(encoder.is_index_found());
+        assert_eq!(encoder.get_mechanical_angle(), Ok(0));
+        pins.steps(&mut encoder, 4);
+        assert_eq!(encoder.get_mechanical_angle(), Ok(16384));
+
+        // a turn later, within a step of where it was, is the same index.
+        pins.steps(&mut encoder, 13);
+        pins.pulse_index(&mut encoder);
+        assert_eq!(encoder.get_index_misses(), 0);
+        assert_eq!(encoder.get_mechanical_angle(), Ok(4096));
+
+        // half a turn off means counts were lost, the index is taken as zero again.
+        pins.steps(&mut encoder, 7);
+        pins.pulse_index(&mut encoder);
+        assert_eq!(encoder.get_index_misses(), 1);
+        assert_eq!(encoder.get_mechanical_angle(), Ok(0));
+    }
+}
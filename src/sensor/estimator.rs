@@ -0,0 +1,325 @@
+// Turn raw angle readings into a filtered angle and speed.
+// Differencing readings amplifies their noise by the loop rate, so the speed has to be filtered,
+// and a plain filter on it lags at low speed or lets noise through at high speed.
+// Estimators that model the motion instead follow the angle without lag at a chosen bandwidth.
+
+pub trait AngleEstimator {
+    // a reading of the unwrapped angle, dt seconds after the last call.
+    fn update(&mut self, measured_rads: f32, dt: f32);
+    // no reading this time, carry on from the estimate.
+    fn predict(&mut self, dt: f32);
+    // start again from a known angle, standing still.
+    fn reset(&mut self, rads: f32);
+
+    fn get_rads(&self) -> f32;
+    fn get_rads_per_s(&self) -> f32;
+    // only for the estimators that track it.
+    fn get_rads_per_s2(&self) -> Option<f32> {
+        None
+    }
+}
+
+// The angle as read, and an exponential filter on the differenced speed.
+// Laggy at low speed and noisy at high speed, but with nothing to tune.
+pub struct ExponentialFilter {
+    // weight of each new speed, the rest is kept from before.
+    pub weight: f32,
+    rads: f32,
+    rads_per_s: f32,
+}
+
+impl ExponentialFilter {
+    pub fn new(weight: f32) -> Self {
+        ExponentialFilter {
+            weight,
+            rads: 0.0,
+            rads_per_s: 0.0,
+        }
+    }
+}
+
+impl Default for ExponentialFilter {
+    fn default() -> Self {
+        Self::new(0.01)
+    }
+}
+
+impl AngleEstimator for ExponentialFilter {
+    fn update(&mut self, measured_rads: f32, dt: f32) {
+        // a clock that has not moved tells nothing about speed.
+        if dt > 0.0 {
+            self.rads_per_s = (1.0 - self.weight) * self.rads_per_s
+                + self.weight * (measured_rads - self.rads) / dt;
+        }
+        self.rads = measured_rads;
+    }
+
+    fn predict(&mut self, dt: f32) {
+        self.rads += self.rads_per_s * dt;
+    }
+
+    fn reset(&mut self, rads: f32) {
+        self.rads = rads;
+        self.rads_per_s = 0.0;
+    }
+
+    fn get_rads(&self) -> f32 {
+        self.rads
+    }
+
+    fn get_rads_per_s(&self) -> f32 {
+        self.rads_per_s
+    }
+}
+
+// Kalman filter on angle and speed, with the acceleration as white noise.
+// Settles to something much like a pll, but finds its own gains from the noise levels,
+// and picks up faster after a reset since it knows how unsure it is.
+pub struct KalmanFilter {
+    // variance of the acceleration, (rad/s^2)^2.
+    pub acceleration_noise: f32,
+    // variance of a reading, rad^2.
+    pub measurement_noise: f32,
+
+    rads: f32,
+    rads_per_s: f32,
+    // covariance of angle and speed.
+    p: [[f32; 2]; 2],
+}
+
+impl KalmanFilter {
+    pub fn new(acceleration_noise: f32, measurement_noise: f32) -> Self {
+        let mut filter = KalmanFilter {
+            acceleration_noise,
+            measurement_noise,
+            rads: 0.0,
+            rads_per_s: 0.0,
+            p: [[0.0; 2]; 2],
+        };
+        filter.reset(0.0);
+        filter
+    }
+
+    // Tuned for a bandwidth in rad/s, for a sensor with the given resolution in bits.
+    // The noise is the quantisation of the sensor, readings any noisier need a lower bandwidth.
+    pub fn from_bandwidth(bandwidth: f32, bit_resolution: u8) -> Self {
+        // finer than the 24 bits of an f32 angle is not seen anyway.
+        let step = core::f32::consts::TAU / (1u32 << bit_resolution.min(24)) as f32;
+        let measurement_noise = step * step / 12.0;
+        // settles to the gains of a pll with this natural frequency and a damping of 0.7.
+        let acceleration_noise = bandwidth * bandwidth * bandwidth * bandwidth * measurement_noise;
+        Self::new(acceleration_noise, measurement_noise)
+    }
+}
+
+impl AngleEstimator for KalmanFilter {
+    fn update(&mut self, measured_rads: f32, dt: f32) {
+        self.predict(dt);
+
+        let innovation = measured_rads - self.rads;
+        let s = self.p[0][0] + self.measurement_noise;
+        let k = [self.p[0][0] / s, self.p[1][0] / s];
+        self.rads += k[0] * innovation;
+        self.rads_per_s += k[1] * innovation;
+
+        let p = self.p;
+        self.p = [
+            [(1.0 - k[0]) * p[0][0], (1.0 - k[0]) * p[0][1]],
+            [p[1][0] - k[1] * p[0][0], p[1][1] - k[1] * p[0][1]],
+        ];
+    }
+
+    fn predict(&mut self, dt: f32) {
+        if dt <= 0.0 {
+            return;
+        }
+        self.rads += self.rads_per_s * dt;
+
+        let p = self.p;
+        let p01 = p[0][1] + dt * p[1][1];
+        let p00 = p[0][0] + dt * (p[1][0] + p01);
+        let p10 = p[1][0] + dt * p[1][1];
+        let q = self.acceleration_noise;
+        let dt2 = dt * dt;
+        self.p = [
+            [p00 + q * dt2 * dt2 / 4.0, p01 + q * dt2 * dt / 2.0],
+            [p10 + q * dt2 * dt / 2.0, p[1][1] + q * dt2],
+        ];
+    }
+
+    fn reset(&mut self, rads: f32) {
+        self.rads = rads;
+        self.rads_per_s = 0.0;
+        // the angle is a reading, the speed could be anything within reason.
+        self.p = [[self.measurement_noise, 0.0], [0.0, 100.0]];
+    }
+
+    fn get_rads(&self) -> f32 {
+        self.rads
+    }
+
+    fn get_rads_per_s(&self) -> f32 {
+        self.rads_per_s
+    }
+}
+
+// Type 2 pll, a tracking observer that follows a steady speed without lag.
+// Critically damped, the bandwidth sets how fast it follows and how much noise gets through.
+// Keep the bandwidth well under the loop rate, the gains are applied once per reading.
+pub struct PllObserver {
+    // rad/s.
+    pub bandwidth: f32,
+    rads: f32,
+    rads_per_s: f32,
+    rads_per_s2: f32,
+}
+
+impl PllObserver {
+    pub fn new(bandwidth: f32) -> Self {
+        PllObserver {
+            bandwidth,
+            rads: 0.0,
+            rads_per_s: 0.0,
+            rads_per_s2: 0.0,
+        }
+    }
+}
+
+impl AngleEstimator for PllObserver {
+    fn update(&mut self, measured_rads: f32, dt: f32) {
+        self.predict(dt);
+
+        let kp = 2.0 * self.bandwidth;
+        let ki = self.bandwidth * self.bandwidth;
+        let error = measured_rads - self.rads;
+        // how hard the speed is pulled is the observer's view of the acceleration.
+        self.rads_per_s2 = ki * error;
+        self.rads_per_s += self.rads_per_s2 * dt;
+        self.rads += kp * error * dt;
+    }
+
+    fn predict(&mut self, dt: f32) {
+        self.rads += self.rads_per_s * dt;
+    }
+
+    fn reset(&mut self, rads: f32) {
+        self.rads = rads;
+        self.rads_per_s = 0.0;
+        self.rads_per_s2 = 0.0;
+    }
+
+    fn get_rads(&self) -> f32 {
+        self.rads
+    }
+
+    fn get_rads_per_s(&self) -> f32 {
+        self.rads_per_s
+    }
+
+    fn get_rads_per_s2(&self) -> Option<f32> {
+        Some(self.rads_per_s2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::clock::MockClock;
+    use crate::sensor::{RotarySensor, RotorState, RotorTracker, SensorError};
+    use core::f32::consts;
+
+    // 10 kHz, well over the bandwidths.
+    const DT: f32 = 0.000_1;
+    const BANDWIDTH: f32 = 200.0;
+
+    // the largest angle seen on the way and the angle and speed at the end, of readings for `seconds`.
+    fn follow(
+        estimator: &mut impl AngleEstimator,
+        seconds: f32,
+        reading: impl Fn(f32) -> f32,
+    ) -> (f32, f32, f32) {
+        estimator.reset(0.0);
+        let mut peak = f32::MIN;
+        for i in 1..=(seconds / DT) as usize {
+            estimator.update(reading(i as f32 * DT), DT);
+            peak = peak.max(estimator.get_rads());
+        }
+        (peak, estimator.get_rads(), estimator.get_rads_per_s())
+    }
+
+    fn step_response(estimator: &mut impl AngleEstimator) {
+        let (peak, rads, rads_per_s) = follow(estimator, 0.1, |_| 0.1);
+        assert!((rads - 0.1).abs() < 1e-4, "{rads}");
+        assert!(rads_per_s.abs() < 0.01, "{rads_per_s}");
+        // about critically damped, so next to no overshoot.
+        assert!(peak < 0.1 * 1.2, "{peak}");
+    }
+
+    fn follows_a_ramp(estimator: &mut impl AngleEstimator) {
+        let (_, rads, rads_per_s) = follow(estimator, 0.1, |t| 50.0 * t);
+        assert!((rads - 5.0).abs() < 1e-3, "{rads}");
+        assert!((rads_per_s - 50.0).abs() < 0.5, "{rads_per_s}");
+    }
+
+    // a sensor turning at a steady rate, wrapping around at the end of the revolution.
+    struct Turning {
+        reading: u16,
+        step: u16,
+    }
+
+    impl RotarySensor for Turning {
+        fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
+            self.reading = self.reading.wrapping_add(self.step);
+            Ok(self.reading)
+        }
+    }
+
+    fn carries_on_past_a_revolution(estimator: impl AngleEstimator) {
+        let clock = MockClock::new();
+        let sensor = Turning {
+            reading: 60000,
+            step: 52,
+        };
+        let mut rotor = RotorState::with_estimator(&clock, sensor, estimator);
+        let rads_per_s = 52.0 * consts::TAU / 65536.0 / DT;
+        let mut prior_rads = rotor.get_rads();
+        // four revolutions, settled after the first.
+        for i in 0..5000 {
+            clock.advance_us(100);
+            rotor.update();
+            let rads = rotor.get_rads();
+            assert!(rads > prior_rads, "{rads} after {prior_rads}");
+            prior_rads = rads;
+            if i > 1000 {
+                let speed = rotor.get_rads_per_s();
+                assert!((speed - rads_per_s).abs() < 0.01 * rads_per_s, "{speed}");
+            }
+        }
+        assert!(prior_rads > 4.0 * consts::TAU, "{prior_rads}");
+    }
+
+    #[test]
+    fn kalman_filter() {
+        let mut filter = KalmanFilter::from_bandwidth(BANDWIDTH, 14);
+        step_response(&mut filter);
+        follows_a_ramp(&mut filter);
+        carries_on_past_a_revolution(filter);
+    }
+
+    #[test]
+    fn kalman_filter_of_a_very_fine_sensor() {
+        // more bits than a shift can take.
+        let filter = KalmanFilter::from_bandwidth(BANDWIDTH, 32);
+        let limit = KalmanFilter::from_bandwidth(BANDWIDTH, 24);
+        assert_eq!(filter.measurement_noise, limit.measurement_noise);
+        assert!(filter.measurement_noise > 0.0);
+    }
+
+    #[test]
+    fn pll_observer() {
+        let mut pll = PllObserver::new(BANDWIDTH);
+        step_response(&mut pll);
+        follows_a_ramp(&mut pll);
+        carries_on_past_a_revolution(pll);
+    }
+}
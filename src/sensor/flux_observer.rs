@@ -0,0 +1,258 @@
+// Work out the rotor angle from the voltages and currents alone, for motors without a sensor.
+//
+// The nonlinear flux observer from Ortega et al, the same one used by vesc.
+// The stator flux is the integral of v - Ri, and the magnet's part of it is what is left after taking out Li.
+// Pure integration drifts, so the estimate is pulled toward the circle of radius flux linkage.
+// A pll then smooths the angle and gives the speed.
+//
+// Back emf vanishes at standstill, so the motor is started with a forced rotating current (I-f)
+// and only handed over to the observer once it spins fast enough and the observer has locked on.
+
+use core::f32::consts;
+use micromath::F32;
+
+use super::RotorTracker;
+use crate::bldc_motor::BLDCMotorSpecification;
+use crate::common::clock::{Clock, Instant};
+use crate::common::em;
+
+pub struct FluxObserverConfig {
+    // how quickly the flux estimate is pulled onto the circle, rad/s.
+    pub gain: f32,
+    // bandwidth of the pll that tracks the observed angle, rad/s.
+    pub pll_bandwidth: f32,
+    // q current forced into the motor during startup, amps.
+    pub startup_current: f32,
+    // how fast the forced field speeds up during startup, mechanical rad/s^2.
+    pub startup_acceleration: f32,
+    // speed at which the observer takes over, mechanical rad/s.
+    pub handover_speed: f32,
+    // time spent blending from the forced angle to the observed angle, seconds.
+    pub handover_time: f32,
+    // the observer has locked on once the pll is within this of the observed angle, electrical rad,
+    pub lock_angle_error: f32,
+    // and the pll speed within this fraction of the forced speed.
+    // a rotor that stalled or never followed shows little back emf, and no speed.
+    pub lock_speed_error: f32,
+    // give up on starting after this long, seconds.
+    pub startup_timeout: f32,
+}
+
+pub const DEFAULT_FLUX_OBSERVER_CONFIG: FluxObserverConfig = FluxObserverConfig {
+    gain: 1000.0,
+    pll_bandwidth: 500.0,
+    startup_current: 0.5,
+    startup_acceleration: 50.0,
+    handover_speed: 30.0,
+    handover_time: 0.1,
+    lock_angle_error: 0.2,
+    lock_speed_error: 0.2,
+    startup_timeout: 3.0,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObserverPhase {
+    // only the observer, whether it can be trusted or not.
+    Observing,
+    // the angle is forced and accelerating, the observer runs in the background.
+    // this lasts until the observer locks on, however long that takes.
+    Startup,
+    // blending from the forced angle to the observed angle.
+    Handover { elapsed: f32 },
+}
+
+pub struct FluxObserver<'a, T: Clock> {
+    clock: &'a T,
+    config: FluxObserverConfig,
+    pole_pairs: f32,
+    phase_resistance: f32,
+    phase_inductance: f32,
+    flux_linkage: f32,
+
+    phase: ObserverPhase,
+    prior_update: Instant,
+
+    // applied voltage and measured current from the last foc loop.
+    v: em::Valphabeta,
+    i: em::Ialphabeta,
+    // stator flux estimate, volt seconds.
+    flux: em::Valphabeta,
+
+    // electrical, not wrapped.
+    pll_angle: f32,
+    pll_speed: f32,
+    // observed angle less the pll angle, from the last update.
+    pll_error: f32,
+    forced_angle: f32,
+    forced_speed: f32,
+    // what is given out, electrical and not wrapped.
+    angle: f32,
+    speed: f32,
+}
+
+impl<'a, T: Clock> FluxObserver<'a, T> {
+    pub fn new(
+        clock: &'a T,
+        specification: &BLDCMotorSpecification,
+        config: FluxObserverConfig,
+    ) -> Self {
+        let flux_linkage = specification.flux_linkage();
+        FluxObserver {
+            clock,
+            config,
+            pole_pairs: specification.pole_pairs as f32,
+            phase_resistance: specification.phase_resistance,
+            phase_inductance: specification.phase_inductance,
+            flux_linkage,
+
+            phase: ObserverPhase::Observing,
+            prior_update: clock.now(),
+
+            v: em::Valphabeta {
+                alpha: 0.0,
+                beta: 0.0,
+            },
+            i: em::Ialphabeta {
+                alpha: 0.0,
+                beta: 0.0,
+            },
+            // somewhere on the circle, the observer finds its own way from there.
+            flux: em::Valphabeta {
+                alpha: 0.0,
+                beta: -flux_linkage,
+            },
+
+            pll_angle: 0.0,
+            pll_speed: 0.0,
+            pll_error: 0.0,
+            forced_angle: 0.0,
+            forced_speed: 0.0,
+            angle: 0.0,
+            speed: 0.0,
+        }
+    }
+
+    // Start forcing the angle from where it is now.
+    // Run the motor in current torque mode with the startup current until `is_observing`,
+    // or until the startup timeout if it never gets there.
+    pub fn start(&mut self) {
+        self.forced_angle = self.angle;
+        self.forced_speed = 0.0;
+        self.phase = ObserverPhase::Startup;
+    }
+
+    pub fn get_phase(&self) -> ObserverPhase {
+        self.phase
+    }
+
+    pub fn is_observing(&self) -> bool {
+        self.phase == ObserverPhase::Observing
+    }
+
+    // Whether the observer follows a rotor turning with the forced field.
+    pub fn is_locked(&self) -> bool {
+        F32(self.pll_error).abs().0 < self.config.lock_angle_error
+            && F32(self.pll_speed - self.forced_speed).abs().0
+                <= self.config.lock_speed_error * F32(self.forced_speed).abs().0
+    }
+
+    pub fn get_config(&self) -> &FluxObserverConfig {
+        &self.config
+    }
+
+    // electrical angle straight out of the observer, wrapped, for checking against the pll.
+    pub fn get_observed_electrical_angle(&self) -> f32 {
+        let (flux_alpha, flux_beta) = self.magnet_flux();
+        // the magnet flux is (sin, -cos) of the electrical angle in this crate's convention.
+        F32(flux_alpha).atan2(F32(-flux_beta)).0
+    }
+
+    fn magnet_flux(&self) -> (f32, f32) {
+        (
+            self.flux.alpha - self.phase_inductance * self.i.alpha,
+            self.flux.beta - self.phase_inductance * self.i.beta,
+        )
+    }
+
+    fn wrap(angle: f32) -> f32 {
+        angle - consts::TAU * F32(angle / consts::TAU).round().0
+    }
+}
+
+impl<T: Clock> RotorTracker for FluxObserver<'_, T> {
+    fn update(&mut self) {
+        let now = self.clock.now();
+        let dt = (now - self.prior_update).to_micros() as f32 / 1_000_000.0;
+        self.prior_update = now;
+        if dt <= 0.0 {
+            return;
+        }
+
+        // observer, the voltage was held over the last loop.
+        let (flux_alpha, flux_beta) = self.magnet_flux();
+        let error = self.flux_linkage * self.flux_linkage
+            - (flux_alpha * flux_alpha + flux_beta * flux_beta);
+        let gamma = self.config.gain / (self.flux_linkage * self.flux_linkage);
+        self.flux.alpha += (self.v.alpha - self.phase_resistance * self.i.alpha
+            + 0.5 * gamma * flux_alpha * error)
+            * dt;
+        self.flux.beta += (self.v.beta - self.phase_resistance * self.i.beta
+            + 0.5 * gamma * flux_beta * error)
+            * dt;
+
+        // critically damped type 2 pll.
+        let kp = 2.0 * self.config.pll_bandwidth;
+        let ki = self.config.pll_bandwidth * self.config.pll_bandwidth;
+        self.pll_error = Self::wrap(self.get_observed_electrical_angle() - self.pll_angle);
+        self.pll_speed += ki * self.pll_error * dt;
+        self.pll_angle += (self.pll_speed + kp * self.pll_error) * dt;
+
+        match self.phase {
+            ObserverPhase::Observing => {
+                self.angle = self.pll_angle;
+                self.speed = self.pll_speed;
+            }
+            ObserverPhase::Startup => {
+                let handover_speed = self.config.handover_speed * self.pole_pairs;
+                self.forced_speed = (self.forced_speed
+                    + self.config.startup_acceleration * self.pole_pairs * dt)
+                    .min(handover_speed);
+                self.forced_angle += self.forced_speed * dt;
+                if self.forced_speed >= handover_speed && self.is_locked() {
+                    self.phase = ObserverPhase::Handover { elapsed: 0.0 };
+                }
+                self.angle = self.forced_angle;
+                self.speed = self.forced_speed;
+            }
+            ObserverPhase::Handover { elapsed } => {
+                self.forced_angle += self.forced_speed * dt;
+                let elapsed = elapsed + dt;
+                let blend = (elapsed / self.config.handover_time).min(1.0);
+                self.angle =
+                    self.forced_angle + blend * Self::wrap(self.pll_angle - self.forced_angle);
+                self.speed = self.forced_speed + blend * (self.pll_speed - self.forced_speed);
+                self.phase = if blend < 1.0 {
+                    ObserverPhase::Handover { elapsed }
+                } else {
+                    // carry on from the blended angle so nothing jumps.
+                    self.pll_angle = self.angle;
+                    ObserverPhase::Observing
+                };
+            }
+        }
+    }
+
+    fn observe(&mut self, v_abc: &em::Vabc, i_abc: &em::Iabc) {
+        self.v = v_abc.clarke_transformation();
+        self.i = i_abc.clarke_transformation();
+    }
+
+    // relative to wherever the rotor was at power up, there is no absolute reference without a sensor.
+    fn get_rads(&self) -> f32 {
+        self.angle / self.pole_pairs
+    }
+
+    fn get_rads_per_s(&self) -> f32 {
+        self.speed / self.pole_pairs
+    }
+}
@@ -0,0 +1,387 @@
+// Three digital hall sensors 120 electrical degrees apart, as found in many hub and drone motors.
+// They only tell which sixth of an electrical revolution the rotor is in,
+// so the angle between edges is interpolated from how long the last sixth took.
+// The pins are polled on every reading, so read at least a few times per sixth.
+
+use super::SensorError;
+use embedded_hal::digital::InputPin;
+
+use super::RotarySensor;
+use crate::common::clock::{Clock, Instant};
+
+// sector of each hall state, with a as the most significant bit.
+// 000 and 111 can not happen with working sensors.
+const SECTORS: [Option<u8>; 8] = [
+    None,
+    Some(0),
+    Some(4),
+    Some(5),
+    Some(2),
+    Some(1),
+    Some(3),
+    None,
+];
+
+pub struct HallSensor<'a, A: InputPin, B: InputPin, C: InputPin, T: Clock> {
+    a: A,
+    b: B,
+    c: C,
+    clock: &'a T,
+    pole_pairs: u8,
+
+    // sixth of an electrical revolution, 0 to 5.
+    sector: Option<u8>,
+    // number of full electrical revolutions, rounded to negative infinity
+    electrical_revs: i32,
+    // direction of the last step, 1 or -1.
+    direction: i8,
+    // when the last edge came, and how long the sector before it took.
+    prior_edge: Instant,
+    sector_period_us: Option<u64>,
+
+    // times a 000 or 111 state was read.
+    invalid_states: u32,
+    // times the state jumped more than one sector between readings.
+    skipped_sectors: u32,
+}
+
+impl<'a, A: InputPin, B: InputPin, C: InputPin, T: Clock> HallSensor<'a, A, B, C, T> {
+    pub fn new(a: A, b: B, c: C, clock: &'a T, pole_pairs: u8) -> Self {
+        let mut sensor = HallSensor {
+            a,
+            b,
+            c,
+            clock,
+            pole_pairs,
+
+            sector: None,
+            electrical_revs: 0,
+            direction: 1,
+            prior_edge: clock.now(),
+            sector_period_us: None,
+
+            invalid_states: 0,
+            skipped_sectors: 0,
+        };
+        // a bad first reading is fine, the next good one starts the tracking.
+        let _ = sensor.poll();
+        sensor
+    }
+
+    pub fn release(self) -> (A, B, C) {
+        (self.a, self.b, self.c)
+    }
+
+    pub fn get_sector(&self) -> Option<u8> {
+        self.sector
+    }
+
+    pub fn get_electrical_revs(&self) -> i32 {
+        self.electrical_revs
+    }
+
+    pub fn get_invalid_states(&self) -> u32 {
+        self.invalid_states
+    }
+
+    pub fn get_skipped_sectors(&self) -> u32 {
+        self.skipped_sectors
+    }
+
+    // electrical radians per second from the last full sector, 0 when it has not moved.
+    pub fn get_electrical_speed(&self) -> f32 {
+        match self.get_sector_period_us() {
+            Some(period_us) => {
+                self.direction as f32 * (core::f32::consts::TAU / 6.0) * 1_000_000.0
+                    / period_us as f32
+            }
+            None => 0.0,
+        }
+    }
+
+    // Read the pins and follow the sector sequence.
+    pub fn poll(&mut self) -> Result<(), SensorError> {
+        let state = (self.a.is_high().map_err(|_| SensorError::Bus)? as usize) << 2
+            | (self.b.is_high().map_err(|_| SensorError::Bus)? as usize) << 1
+            | (self.c.is_high().map_err(|_| SensorError::Bus)? as usize);
+
+        let sector = match SECTORS[state] {
+            Some(sector) => sector,
+            None => {
+                self.invalid_states += 1;
+                return Err(SensorError::InvalidState);
+            }
+        };
+
+        let prior_sector = match self.sector {
+            Some(prior_sector) => prior_sector,
+            None => {
+                self.sector = Some(sector);
+                self.prior_edge = self.clock.now();
+                return Ok(());
+            }
+        };
+        if sector == prior_sector {
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        let step: i8 = match (sector + 6 - prior_sector) % 6 {
+            1 => 1,
+            5 => -1,
+            // missed at least one edge, the direction is a guess.
+            _ => {
+                self.skipped_sectors += 1;
+                self.direction
+            }
+        };
+
+        // a step over 5 to 0 or back completes an electrical revolution.
+        if step > 0 && sector < prior_sector {
+            self.electrical_revs += 1;
+        } else if step < 0 && sector > prior_sector {
+            self.electrical_revs -= 1;
+        }
+
+        // the period only means something if the last sector was crossed, not turned back in.
+        self.sector_period_us = if step == self.direction {
+            Some((now - self.prior_edge).to_micros().max(1))
+        } else {
+            None
+        };
+        self.direction = step;
+        self.sector = Some(sector);
+        self.prior_edge = now;
+        Ok(())
+    }
+
+    // How long the last full sector took, as long as this one has not taken longer.
+    // Past that the rotor has slowed down or stopped and the last speed says nothing.
+    fn get_sector_period_us(&self) -> Option<u64> {
+        let period_us = self.sector_period_us?;
+        let elapsed_us = (self.clock.now() - self.prior_edge).to_micros();
+        if elapsed_us > period_us {
+            None
+        } else {
+            Some(period_us)
+        }
+    }
+
+    // fraction of an electrical revolution, with the angle since the last edge interpolated.
+    fn electrical_fraction(&self, sector: u8) -> f32 {
+        let progress = match self.get_sector_period_us() {
+            Some(period_us) => {
+                // the rotor can not have left the sector without an edge.
+                let elapsed_us = (self.clock.now() - self.prior_edge).to_micros();
+                (elapsed_us as f32 / period_us as f32).min(0.999)
+            }
+            // no idea where in the sector it is, the middle is the least wrong.
+            None => 0.5,
+        };
+
+        let in_sector = if self.direction > 0 {
+            progress
+        } else {
+            1.0 - progress
+        };
+        (sector as f32 + in_sector) / 6.0
+    }
+}
+
+impl<A: InputPin, B: InputPin, C: InputPin, T: Clock> RotarySensor for HallSensor<'_, A, B, C, T> {
+    fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
+        self.poll()?;
+        let sector = self.sector.ok_or(SensorError::InvalidState)?;
+
+        let pole_pairs = self.pole_pairs.max(1) as i32;
+        let electrical_revs = self.electrical_revs.rem_euclid(pole_pairs) as f32;
+        let revs = (electrical_revs + self.electrical_fraction(sector)) / pole_pairs as f32;
+        Ok(((revs * 65536.0) as u32 & 0xffff) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::clock::MockClock;
+    use core::cell::Cell;
+    use embedded_hal::digital::ErrorType;
+
+    // a pin that reads whatever the test last set it to.
+    struct MockPin<'a>(&'a Cell<bool>);
+
+    impl ErrorType for MockPin<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl InputPin for MockPin<'_> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.0.get())
+        }
+    }
+
+    type MockHallSensor<'a> = HallSensor<'a, MockPin<'a>, MockPin<'a>, MockPin<'a>, MockClock>;
+
+    struct Pins {
+        a: Cell<bool>,
+        b: Cell<bool>,
+        c: Cell<bool>,
+    }
+
+    impl Pins {
+        // in sector 0.
+        fn new() -> Self {
+            let pins = Pins {
+                a: Cell::new(false),
+                b: Cell::new(false),
+                c: Cell::new(false),
+            };
+            pins.set_state(0b001);
+            pins
+        }
+
+        fn sensor<'a>(&'a self, clock: &'a MockClock, pole_pairs: u8) -> MockHallSensor<'a> {
+            HallSensor::new(
+                MockPin(&self.a),
+                MockPin(&self.b),
+                MockPin(&self.c),
+                clock,
+                pole_pairs,
+            )
+        }
+
+        fn set_state(&self, state: usize) {
+            self.a.set(state & 0b100 != 0);
+            self.b.set(state & 0b010 != 0);
+            self.c.set(state & 0b001 != 0);
+        }
+
+        fn set_sector(&self, sector: u8) {
+            let state = SECTORS
+                .iter()
+                .position(|entry| *entry == Some(sector))
+                .unwrap();
+            self.set_state(state);
+        }
+
+        // one sector forward or back every period, polling in between.
+        fn steps(&self, sensor: &mut MockHallSensor<'_>, clock: &MockClock, steps: i32) {
+            for _ in 0..steps.abs() {
+                clock.advance_us(1000);
+                let sector = sensor.get_sector().unwrap() as i32 + steps.signum();
+                self.set_sector(sector.rem_euclid(6) as u8);
+                sensor.poll().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn follows_the_sequence_both_ways() {
+        let clock = MockClock::new();
+        let pins = Pins::new();
+        let mut sensor = pins.sensor(&clock, 1);
+        assert_eq!(sensor.get_sector(), Some(0));
+
+        for sector in [1, 2, 3, 4, 5, 0] {
+            pins.steps(&mut sensor, &clock, 1);
+            assert_eq!(sensor.get_sector(), Some(sector));
+        }
+        assert_eq!(sensor.get_electrical_revs(), 1);
+        for sector in [5, 4, 3, 2, 1, 0] {
+            pins.steps(&mut sensor, &clock, -1);
+            assert_eq!(sensor.get_sector(), Some(sector));
+        }
+        assert_eq!(sensor.get_electrical_revs(), 0);
+        assert_eq!(sensor.get_skipped_sectors(), 0);
+    }
+
+    #[test]
+    fn impossible_states_are_reported() {
+        let clock = MockClock::new();
+        let pins = Pins::new();
+        let mut sensor = pins.sensor(&clock, 1);
+        for state in [0b000, 0b111] {
+            pins.set_state(state);
+            assert_eq!(sensor.poll(), Err(SensorError::InvalidState));
+            assert_eq!(
+                sensor.get_mechanical_angle(),
+                Err(SensorError::InvalidState)
+            );
+        }
+        assert_eq!(sensor.get_invalid_states(), 4);
+        // the sector is kept until a good state comes back.
+        assert_eq!(sensor.get_sector(), Some(0));
+        pins.set_sector(1);
+        assert_eq!(sensor.poll(), Ok(()));
+        assert_eq!(sensor.get_sector(), Some(1));
+    }
+
+    #[test]
+    fn counts_revolutions_by_pole_pairs() {
+        let clock = MockClock::new();
+        let pins = Pins::new();
+        let mut sensor = pins.sensor(&clock, 4);
+        // a quarter turn, and a turn and a quarter, are the same mechanical angle.
+        pins.steps(&mut sensor, &clock, 6);
+        assert_eq!(sensor.get_electrical_revs(), 1);
+        let angle = sensor.get_mechanical_angle().unwrap();
+        pins.steps(&mut sensor, &clock, 24);
+        assert_eq!(sensor.get_electrical_revs(), 5);
+        assert_eq!(sensor.get_mechanical_angle(), Ok(angle));
+        // and back past zero.
+        pins.steps(&mut sensor, &clock, -36);
+        assert_eq!(sensor.get_electrical_revs(), -1);
+    }
+
+    #[test]
+    fn interpolates_between_edges() {
+        let clock = MockClock::new();
+        let pins = Pins::new();
+        let mut sensor = pins.sensor(&clock, 1);
+        // a sector per ms forward, now at the start of sector 2.
+        pins.steps(&mut sensor, &clock, 2);
+        let speed = core::f32::consts::TAU / 6.0 * 1000.0;
+        assert!((sensor.get_electrical_speed() - speed).abs() < 1.0);
+
+        let sixth = 65536.0 / 6.0;
+        for (elapsed_us, fraction) in [(0, 0.0), (250, 0.25), (500, 0.5)] {
+            clock.set(Instant::from_ticks(2000 + elapsed_us));
+            let angle = sensor.get_mechanical_angle().unwrap() as f32;
+            assert!((angle - (2.0 + fraction) * sixth).abs() < 2.0, "{angle}");
+        }
+
+        // going back, it moves down from the top of the sector.
+        pins.steps(&mut sensor, &clock, -1);
+        pins.steps(&mut sensor, &clock, -1);
+        assert!(sensor.get_electrical_speed() < 0.0);
+        clock.advance_us(250);
+        let angle = sensor.get_mechanical_angle().unwrap() as f32;
+        assert!((angle - 0.75 * sixth).abs() < 2.0, "{angle}");
+    }
+
+    #[test]
+    fn stalled_rotor_has_no_speed() {
+        let clock = MockClock::new();
+        let pins = Pins::new();
+        let mut sensor = pins.sensor(&clock, 1);
+        pins.steps(&mut sensor, &clock, 3);
+        assert!(sensor.get_electrical_speed() > 0.0);
+
+        // longer than the last sector took, so it slowed down or stopped somewhere in this one.
+        clock.advance_us(1001);
+        assert_eq!(sensor.get_electrical_speed(), 0.0);
+        let angle = sensor.get_mechanical_angle().unwrap() as f32;
+        assert!((angle - 3.5 * 65536.0 / 6.0).abs() < 2.0, "{angle}");
+        clock.advance_us(1_000_000);
+        assert_eq!(sensor.get_electrical_speed(), 0.0);
+
+        // the next edge gives the speed again, from how long it took.
+        pins.steps(&mut sensor, &clock, 1);
+        let speed = core::f32::consts::TAU / 6.0 * 1_000_000.0 / 1_002_001.0;
+        assert!((sensor.get_electrical_speed() - speed).abs() < 0.001);
+    }
+}
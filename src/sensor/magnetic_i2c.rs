@@ -4,7 +4,8 @@
 //          This is already done is the exisitng arduino foc library.
 
 use super::RotarySensor;
-use embedded_hal::i2c::{ErrorKind, I2c};
+use super::SensorError;
+use embedded_hal::i2c::I2c;
 
 pub struct MageticI2CConfig {
     pub chip_address: u8,
@@ -13,7 +14,7 @@ pub struct MageticI2CConfig {
     pub data_start_bit: u8,
 }
 
-// NOT the complete set of features for as5600, but these are the ones simple foc have.
+// the angle only, the rest of the as5600 is reached through the register functions below.
 pub const AS5600_CONFIG: MageticI2CConfig = MageticI2CConfig {
     chip_address: 0x36,
     bit_resolution: 12,
@@ -21,6 +22,179 @@ pub const AS5600_CONFIG: MageticI2CConfig = MageticI2CConfig {
     data_start_bit: 11,
 };
 
+// as5600 registers besides the angle.
+const AS5600_CONF_REGISTER: u8 = 0x07;
+const AS5600_STATUS_REGISTER: u8 = 0x0b;
+const AS5600_AGC_REGISTER: u8 = 0x1a;
+const AS5600_MAGNITUDE_REGISTER: u8 = 0x1b;
+
+// whether the magnet is where it should be, from the STATUS register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AS5600Status {
+    // MD, there is a magnet.
+    pub magnet_detected: bool,
+    // ML, the agc is at its maximum, the magnet is too weak or too far.
+    pub magnet_too_weak: bool,
+    // MH, the agc is at its minimum, the magnet is too strong or too close.
+    pub magnet_too_strong: bool,
+}
+
+impl AS5600Status {
+    fn from_register(register: u8) -> Self {
+        AS5600Status {
+            magnet_detected: register & (1 << 5) != 0,
+            magnet_too_weak: register & (1 << 4) != 0,
+            magnet_too_strong: register & (1 << 3) != 0,
+        }
+    }
+
+    pub fn is_magnet_ok(&self) -> bool {
+        self.magnet_detected && !self.magnet_too_weak && !self.magnet_too_strong
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AS5600PowerMode {
+    Nominal,
+    // polling every 5 ms.
+    LowPower1,
+    // polling every 20 ms.
+    LowPower2,
+    // polling every 100 ms.
+    LowPower3,
+}
+
+// the output holds still until the angle moves this many lsb.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AS5600Hysteresis {
+    Off,
+    Lsb1,
+    Lsb2,
+    Lsb3,
+}
+
+// step response time of the slow filter, in multiples of the fastest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AS5600SlowFilter {
+    X16,
+    X8,
+    X4,
+    X2,
+}
+
+// how many lsb the angle has to jump for the fast filter to take over from the slow one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AS5600FastFilterThreshold {
+    SlowFilterOnly,
+    Lsb6,
+    Lsb7,
+    Lsb9,
+    Lsb10,
+    Lsb18,
+    Lsb21,
+    Lsb24,
+}
+
+// the parts of the CONF register that matter for sensing, the output stage is left as it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AS5600Conf {
+    pub power_mode: AS5600PowerMode,
+    pub hysteresis: AS5600Hysteresis,
+    pub slow_filter: AS5600SlowFilter,
+    pub fast_filter_threshold: AS5600FastFilterThreshold,
+    // drop into the lowest power mode after a minute without movement.
+    pub watchdog: bool,
+}
+
+// the chip's power on values.
+pub const AS5600_DEFAULT_CONF: AS5600Conf = AS5600Conf {
+    power_mode: AS5600PowerMode::Nominal,
+    hysteresis: AS5600Hysteresis::Off,
+    slow_filter: AS5600SlowFilter::X16,
+    fast_filter_threshold: AS5600FastFilterThreshold::SlowFilterOnly,
+    watchdog: false,
+};
+
+impl AS5600Conf {
+    // bits that belong to the output stage and pwm frequency.
+    const OUTPUT_MASK: u16 = 0b1111 << 4;
+
+    fn from_register(register: u16) -> Self {
+        let power_mode = match register & 0b11 {
+            0 => AS5600PowerMode::Nominal,
+            1 => AS5600PowerMode::LowPower1,
+            2 => AS5600PowerMode::LowPower2,
+            _ => AS5600PowerMode::LowPower3,
+        };
+        let hysteresis = match (register >> 2) & 0b11 {
+            0 => AS5600Hysteresis::Off,
+            1 => AS5600Hysteresis::Lsb1,
+            2 => AS5600Hysteresis::Lsb2,
+            _ => AS5600Hysteresis::Lsb3,
+        };
+        let slow_filter = match (register >> 8) & 0b11 {
+            0 => AS5600SlowFilter::X16,
+            1 => AS5600SlowFilter::X8,
+            2 => AS5600SlowFilter::X4,
+            _ => AS5600SlowFilter::X2,
+        };
+        let fast_filter_threshold = match (register >> 10) & 0b111 {
+            0 => AS5600FastFilterThreshold::SlowFilterOnly,
+            1 => AS5600FastFilterThreshold::Lsb6,
+            2 => AS5600FastFilterThreshold::Lsb7,
+            3 => AS5600FastFilterThreshold::Lsb9,
+            4 => AS5600FastFilterThreshold::Lsb18,
+            5 => AS5600FastFilterThreshold::Lsb21,
+            6 => AS5600FastFilterThreshold::Lsb24,
+            _ => AS5600FastFilterThreshold::Lsb10,
+        };
+        AS5600Conf {
+            power_mode,
+            hysteresis,
+            slow_filter,
+            fast_filter_threshold,
+            watchdog: register & (1 << 13) != 0,
+        }
+    }
+
+    fn to_register(self) -> u16 {
+        let power_mode = match self.power_mode {
+            AS5600PowerMode::Nominal => 0,
+            AS5600PowerMode::LowPower1 => 1,
+            AS5600PowerMode::LowPower2 => 2,
+            AS5600PowerMode::LowPower3 => 3,
+        };
+        let hysteresis = match self.hysteresis {
+            AS5600Hysteresis::Off => 0,
+            AS5600Hysteresis::Lsb1 => 1,
+            AS5600Hysteresis::Lsb2 => 2,
+            AS5600Hysteresis::Lsb3 => 3,
+        };
+        let slow_filter = match self.slow_filter {
+            AS5600SlowFilter::X16 => 0,
+            AS5600SlowFilter::X8 => 1,
+            AS5600SlowFilter::X4 => 2,
+            AS5600SlowFilter::X2 => 3,
+        };
+        // the codes are not in order of threshold.
+        let fast_filter_threshold = match self.fast_filter_threshold {
+            AS5600FastFilterThreshold::SlowFilterOnly => 0,
+            AS5600FastFilterThreshold::Lsb6 => 1,
+            AS5600FastFilterThreshold::Lsb7 => 2,
+            AS5600FastFilterThreshold::Lsb9 => 3,
+            AS5600FastFilterThreshold::Lsb18 => 4,
+            AS5600FastFilterThreshold::Lsb21 => 5,
+            AS5600FastFilterThreshold::Lsb24 => 6,
+            AS5600FastFilterThreshold::Lsb10 => 7,
+        };
+        power_mode
+            | (hysteresis << 2)
+            | (slow_filter << 8)
+            | (fast_filter_threshold << 10)
+            | ((self.watchdog as u16) << 13)
+    }
+}
+
 pub struct MageticI2C<I: I2c> {
     bus: I,
     config: MageticI2CConfig,
@@ -38,35 +212,92 @@ where
         self.bus
     }
 
+    /// Helper function for write-reading 1 byte from the given register.
+    fn read_u8(&mut self, command: u8) -> Result<u8, SensorError> {
+        let mut buffer = [0u8; 1];
+        let result = self
+            .bus
+            .write_read(self.config.chip_address, &[command], &mut buffer);
+        match result {
+            Ok(_) => Ok(buffer[0]),
+            Err(_) => Err(SensorError::Bus),
+        }
+    }
+
     /// Helper function for write-reading 2 bytes from the given register.
-    fn read_u16(&mut self, command: u8) -> Result<u16, ErrorKind> {
+    fn read_u16(&mut self, command: u8) -> Result<u16, SensorError> {
         let mut buffer = [0u8; 2];
         let result = self
             .bus
             .write_read(self.config.chip_address, &[command], &mut buffer);
         match result {
             Ok(_) => Ok(u16::from_be_bytes(buffer)),
-            Err(_) => Err(ErrorKind::Bus),
+            Err(_) => Err(SensorError::Bus),
         }
     }
 
     /// Helper function for writing 2 bytes to the given register.
-    fn write_u16(&mut self, command: u8, bytes: u16) -> Result<(), ErrorKind> {
+    fn write_u16(&mut self, command: u8, bytes: u16) -> Result<(), SensorError> {
         let bytes: [u8; 2] = bytes.to_be_bytes();
         let buffer = [command, bytes[0], bytes[1]];
         let result = self.bus.write(self.config.chip_address, &buffer);
         match result {
             Ok(some) => Ok(some),
-            Err(_) => Err(ErrorKind::Bus),
+            Err(_) => Err(SensorError::Bus),
+        }
+    }
+}
+
+// Only for the as5600, other chips keep other things at these registers.
+impl<I, E> MageticI2C<I>
+where
+    I: I2c<Error = E>,
+{
+    pub fn get_as5600_status(&mut self) -> Result<AS5600Status, SensorError> {
+        let register = self.read_u8(AS5600_STATUS_REGISTER)?;
+        Ok(AS5600Status::from_register(register))
+    }
+
+    // Check the magnet once at boot, before trusting any angle.
+    pub fn check_as5600_magnet(&mut self) -> Result<(), SensorError> {
+        if self.get_as5600_status()?.is_magnet_ok() {
+            Ok(())
+        } else {
+            Err(SensorError::MagnetMissing)
         }
     }
+
+    // gain of the chip's own amplifier, 0 to 255 at 5 V and 0 to 128 at 3.3 V.
+    // somewhere in the middle leaves room for temperature and air gap changes.
+    pub fn get_as5600_agc(&mut self) -> Result<u8, SensorError> {
+        self.read_u8(AS5600_AGC_REGISTER)
+    }
+
+    // strength of the field as the chip sees it, 12 bits.
+    pub fn get_as5600_magnitude(&mut self) -> Result<u16, SensorError> {
+        let register_value = self.read_u16(AS5600_MAGNITUDE_REGISTER)?;
+        Ok(register_value & 0x0fff)
+    }
+
+    pub fn get_as5600_conf(&mut self) -> Result<AS5600Conf, SensorError> {
+        let register_value = self.read_u16(AS5600_CONF_REGISTER)?;
+        Ok(AS5600Conf::from_register(register_value))
+    }
+
+    // Written to the volatile register, so it has to be set again after every power up.
+    pub fn set_as5600_conf(&mut self, conf: AS5600Conf) -> Result<(), SensorError> {
+        // keep the output stage as it is, it may be what something else reads.
+        let register_value = self.read_u16(AS5600_CONF_REGISTER)?;
+        let register_value = (register_value & AS5600Conf::OUTPUT_MASK) | conf.to_register();
+        self.write_u16(AS5600_CONF_REGISTER, register_value)
+    }
 }
 
 impl<I, E> RotarySensor for MageticI2C<I>
 where
     I: I2c<Error = E>,
 {
-    fn get_mechanical_angle(&mut self) -> Result<u16, ErrorKind> {
+    fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
         let register_value = self.read_u16(self.config.angle_register)?;
         let masked_value = register_value
             & ((1 << (self.config.data_start_bit + 1)) - 1)
@@ -75,3 +306,125 @@ where
         Ok(scaled_value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::ErrorKind;
+    use embedded_hal_mock::eh1::i2c::{Mock, Transaction};
+    use std::vec;
+
+    const ADDRESS: u8 = AS5600_CONFIG.chip_address;
+
+    fn sensor(transactions: &[Transaction]) -> MageticI2C<Mock> {
+        MageticI2C::new(Mock::new(transactions), AS5600_CONFIG)
+    }
+
+    fn read(register: u8, bytes: &[u8]) -> Transaction {
+        Transaction::write_read(ADDRESS, vec![register], bytes.to_vec())
+    }
+
+    #[test]
+    fn angle_is_scaled_to_16_bits() {
+        let mut sensor = sensor(&[read(0x0e, &[0x0a, 0xbc]), read(0x0e, &[0xff, 0xff])]);
+        assert_eq!(sensor.get_mechanical_angle(), Ok(0xabc0));
+        // the unused top bits are masked off.
+        assert_eq!(sensor.get_mechanical_angle(), Ok(0xfff0));
+        sensor.release().done();
+    }
+
+    #[test]
+    fn bus_error() {
+        let mut sensor = sensor(&[read(0x0e, &[0, 0]).with_error(ErrorKind::Other)]);
+        assert_eq!(sensor.get_mechanical_angle(), Err(SensorError::Bus));
+        sensor.release().done();
+    }
+
+    #[test]
+    fn status_bits() {
+        let status = AS5600Status::from_register(1 << 5);
+        assert!(status.magnet_detected && !status.magnet_too_weak && !status.magnet_too_strong);
+        let status = AS5600Status::from_register(1 << 4);
+        assert!(!status.magnet_detected && status.magnet_too_weak && !status.magnet_too_strong);
+        let status = AS5600Status::from_register(1 << 3);
+        assert!(!status.magnet_detected && !status.magnet_too_weak && status.magnet_too_strong);
+        // the rest of the register is unused.
+        assert_eq!(
+            AS5600Status::from_register(!0b0011_1000),
+            AS5600Status::from_register(0)
+        );
+    }
+
+    #[test]
+    fn magnet_is_checked() {
+        let mut sensor = sensor(&[
+            read(AS5600_STATUS_REGISTER, &[1 << 5]),
+            read(AS5600_STATUS_REGISTER, &[0]),
+            read(AS5600_STATUS_REGISTER, &[(1 << 5) | (1 << 4)]),
+            read(AS5600_STATUS_REGISTER, &[(1 << 5) | (1 << 3)]),
+        ]);
+        assert_eq!(sensor.check_as5600_magnet(), Ok(()));
+        for _ in 0..3 {
+            assert_eq!(
+                sensor.check_as5600_magnet(),
+                Err(SensorError::MagnetMissing)
+            );
+        }
+        sensor.release().done();
+    }
+
+    #[test]
+    fn agc_and_magnitude() {
+        let mut sensor = sensor(&[
+            read(AS5600_AGC_REGISTER, &[0x80]),
+            read(AS5600_MAGNITUDE_REGISTER, &[0xfa, 0xbc]),
+        ]);
+        assert_eq!(sensor.get_as5600_agc(), Ok(0x80));
+        assert_eq!(sensor.get_as5600_magnitude(), Ok(0x0abc));
+        sensor.release().done();
+    }
+
+    #[test]
+    fn conf_goes_out_and_back_unchanged() {
+        // every code of every field, without the output stage and unused bits.
+        for register in 0..(1 << 14) {
+            let register = register & !AS5600Conf::OUTPUT_MASK;
+            assert_eq!(AS5600Conf::from_register(register).to_register(), register);
+        }
+        assert_eq!(
+            AS5600Conf::from_register(AS5600_DEFAULT_CONF.to_register()),
+            AS5600_DEFAULT_CONF
+        );
+        let conf = AS5600Conf {
+            power_mode: AS5600PowerMode::LowPower2,
+            hysteresis: AS5600Hysteresis::Lsb1,
+            slow_filter: AS5600SlowFilter::X4,
+            fast_filter_threshold: AS5600FastFilterThreshold::Lsb10,
+            watchdog: true,
+        };
+        assert_eq!(
+            conf.to_register(),
+            2 | (1 << 2) | (2 << 8) | (7 << 10) | (1 << 13)
+        );
+    }
+
+    #[test]
+    fn conf_keeps_the_output_stage() {
+        let conf = AS5600Conf {
+            power_mode: AS5600PowerMode::LowPower1,
+            hysteresis: AS5600Hysteresis::Lsb3,
+            slow_filter: AS5600SlowFilter::X2,
+            fast_filter_threshold: AS5600FastFilterThreshold::Lsb6,
+            watchdog: false,
+        };
+        // pwm output and slowest filter with the watchdog on, to be replaced.
+        let mut sensor = sensor(&[
+            read(AS5600_CONF_REGISTER, &[0x20, 0x23]),
+            Transaction::write(ADDRESS, vec![AS5600_CONF_REGISTER, 0x07, 0x2d]),
+            read(AS5600_CONF_REGISTER, &[0x07, 0x2d]),
+        ]);
+        sensor.set_as5600_conf(conf).unwrap();
+        assert_eq!(sensor.get_as5600_conf(), Ok(conf));
+        sensor.release().done();
+    }
+}
@@ -0,0 +1,289 @@
+// Read magnetic sensors through their pwm output, for when the bus is taken or too slow.
+// Each frame of the output starts with a fixed high init period, some sensors then have an error period,
+// then the angle as the length of the high time, then a fixed low exit period.
+// The clock periods come from the datasheets, only their ratio to the whole frame matters here,
+// so the sensor's own oscillator being off by a few percent does not change the angle.
+
+use super::SensorError;
+use embedded_hal::digital::InputPin;
+
+use super::RotarySensor;
+use crate::common::clock::{Clock, Instant};
+
+pub struct MagneticPWMConfig {
+    pub bit_resolution: u8,
+    // lengths of each part of a frame, in periods of the sensor's pwm clock.
+    pub init_clocks: u16,
+    pub error_clocks: u16,
+    pub data_clocks: u16,
+    pub exit_clocks: u16,
+}
+
+pub const AS5600_PWM_CONFIG: MagneticPWMConfig = MagneticPWMConfig {
+    bit_resolution: 12,
+    init_clocks: 128,
+    error_clocks: 0,
+    data_clocks: 4095,
+    exit_clocks: 128,
+};
+
+pub const AS5048_PWM_CONFIG: MagneticPWMConfig = MagneticPWMConfig {
+    bit_resolution: 12,
+    init_clocks: 12,
+    error_clocks: 4,
+    data_clocks: 4095,
+    exit_clocks: 8,
+};
+
+impl MagneticPWMConfig {
+    pub fn frame_clocks(&self) -> u32 {
+        self.init_clocks as u32
+            + self.error_clocks as u32
+            + self.data_clocks as u32
+            + self.exit_clocks as u32
+    }
+}
+
+pub struct MagneticPWM<'a, P: InputPin, T: Clock> {
+    pin: P,
+    clock: &'a T,
+    config: MagneticPWMConfig,
+
+    // level of the pin at the last edge.
+    is_high: bool,
+    prior_rise: Option<Instant>,
+    prior_fall: Option<Instant>,
+    // length of the last full frame, to notice when the output stops.
+    frame_us: Option<u64>,
+
+    // the last frame decoded, nothing has come in yet is a timeout.
+    angle: Result<u16, SensorError>,
+}
+
+impl<'a, P: InputPin, T: Clock> MagneticPWM<'a, P, T> {
+    pub fn new(mut pin: P, clock: &'a T, config: MagneticPWMConfig) -> Self {
+        let is_high = pin.is_high().unwrap_or(false);
+        MagneticPWM {
+            pin,
+            clock,
+            config,
+
+            is_high,
+            prior_rise: None,
+            prior_fall: None,
+            frame_us: None,
+
+            angle: Err(SensorError::Timeout),
+        }
+    }
+
+    pub fn release(self) -> P {
+        self.pin
+    }
+
+    pub fn get_config(&self) -> &MagneticPWMConfig {
+        &self.config
+    }
+
+    // Read the pin and time the edges.
+    // The clock only ticks in microseconds and the frames are around a millisecond, so this costs resolution,
+    // call `on_edge` from a pin interrupt or timer capture with its time stamp instead where it matters.
+    pub fn poll(&mut self) -> Result<(), SensorError> {
+        let is_high = self.pin.is_high().map_err(|_| SensorError::Bus)?;
+        if is_high != self.is_high {
+            self.on_edge(is_high, self.clock.now());
+        }
+        Ok(())
+    }
+
+    // The pin went to `is_high` at `at`.
+    pub fn on_edge(&mut self, is_high: bool, at: Instant) {
+        self.is_high = is_high;
+        if !is_high {
+            self.prior_fall = Some(at);
+            return;
+        }
+
+        // a rise ends one frame and starts the next.
+        if let (Some(rise), Some(fall)) = (self.prior_rise, self.prior_fall) {
+            if rise <= fall && fall <= at {
+                let high_us = (fall - rise).to_micros();
+                let frame_us = (at - rise).to_micros();
+                self.frame_us = Some(frame_us);
+                self.angle = self.decode(high_us, frame_us);
+            }
+        }
+        self.prior_rise = Some(at);
+    }
+
+    fn decode(&self, high_us: u64, frame_us: u64) -> Result<u16, SensorError> {
+        if frame_us == 0 {
+            return Err(SensorError::OutOfRange);
+        }
+        // in clocks of the sensor, rounded to the nearest.
+        let high_clocks =
+            ((high_us * self.config.frame_clocks() as u64 * 2 + frame_us) / (frame_us * 2)) as u32;
+        let padding = self.config.init_clocks as u32 + self.config.error_clocks as u32;
+        // a little short is timing jitter at angle zero, a lot short is not a frame.
+        if 2 * high_clocks < padding {
+            return Err(SensorError::OutOfRange);
+        }
+        let data = high_clocks
+            .saturating_sub(padding)
+            .min(self.config.data_clocks as u32);
+
+        let steps = 1u32 << self.config.bit_resolution;
+        let reading = data * steps / (self.config.data_clocks as u32 + 1);
+        Ok((reading << (16 - self.config.bit_resolution)) as u16)
+    }
+}
+
+impl<P: InputPin, T: Clock> RotarySensor for MagneticPWM<'_, P, T> {
+    fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
+        self.poll()?;
+
+        // no rise for two frames means the output stopped, the last angle is stale.
+        if let (Some(rise), Some(frame_us)) = (self.prior_rise, self.frame_us) {
+            if (self.clock.now() - rise).to_micros() > 2 * frame_us {
+                return Err(SensorError::Timeout);
+            }
+        }
+        self.angle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::clock::MockClock;
+    use core::cell::Cell;
+    use embedded_hal::digital::ErrorType;
+
+    // a pin that reads whatever the test last set it to.
+    struct MockPin<'a>(&'a Cell<bool>);
+
+    impl ErrorType for MockPin<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl InputPin for MockPin<'_> {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.0.get())
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.0.get())
+        }
+    }
+
+    type MockSensor<'a> = MagneticPWM<'a, MockPin<'a>, MockClock>;
+
+    // a frame of `high_us` then `low_us`, decoded on the rise that starts the next.
+    fn send(
+        sensor: &mut MockSensor<'_>,
+        pin: &Cell<bool>,
+        clock: &MockClock,
+        high_us: u64,
+        low_us: u64,
+    ) {
+        pin.set(true);
+        sensor.poll().unwrap();
+        clock.advance_us(high_us);
+        pin.set(false);
+        sensor.poll().unwrap();
+        clock.advance_us(low_us);
+        pin.set(true);
+        sensor.poll().unwrap();
+    }
+
+    // the angle of a frame with `data` clocks of angle in it, at `us_per_clock`.
+    fn angle_of(
+        config: MagneticPWMConfig,
+        data: u64,
+        us_per_clock: u64,
+    ) -> Result<u16, SensorError> {
+        let pin = Cell::new(false);
+        let clock = MockClock::new();
+        let padding = config.init_clocks as u64 + config.error_clocks as u64;
+        let frame = config.frame_clocks() as u64;
+        let mut sensor = MagneticPWM::new(MockPin(&pin), &clock, config);
+        let high = padding + data;
+        send(
+            &mut sensor,
+            &pin,
+            &clock,
+            high * us_per_clock,
+            (frame - high) * us_per_clock,
+        );
+        sensor.get_mechanical_angle()
+    }
+
+    #[test]
+    fn nothing_yet_is_a_timeout() {
+        let pin = Cell::new(false);
+        let clock = MockClock::new();
+        let mut sensor = MagneticPWM::new(MockPin(&pin), &clock, AS5600_PWM_CONFIG);
+        assert_eq!(sensor.get_mechanical_angle(), Err(SensorError::Timeout));
+    }
+
+    #[test]
+    fn padding_is_taken_off_and_scaled_to_16_bits() {
+        for config in [AS5600_PWM_CONFIG, AS5048_PWM_CONFIG] {
+            assert_eq!(angle_of(config, 0, 1), Ok(0));
+        }
+        for config in [AS5600_PWM_CONFIG, AS5048_PWM_CONFIG] {
+            assert_eq!(angle_of(config, 2048, 1), Ok(0x8000));
+        }
+        for config in [AS5600_PWM_CONFIG, AS5048_PWM_CONFIG] {
+            assert_eq!(angle_of(config, 4095, 1), Ok(0xfff0));
+        }
+    }
+
+    #[test]
+    fn only_the_ratio_of_high_to_frame_matters() {
+        // the sensor's clock running at a third of the speed.
+        assert_eq!(angle_of(AS5600_PWM_CONFIG, 1000, 3), Ok(1000 << 4));
+        // the low time sets the frame too, the same high time in a frame twice as long is half the clocks.
+        let pin = Cell::new(false);
+        let clock = MockClock::new();
+        let mut sensor = MagneticPWM::new(MockPin(&pin), &clock, AS5600_PWM_CONFIG);
+        let frame = AS5600_PWM_CONFIG.frame_clocks() as u64;
+        send(
+            &mut sensor,
+            &pin,
+            &clock,
+            128 + 2048,
+            2 * frame - 128 - 2048,
+        );
+        assert_eq!(
+            sensor.get_mechanical_angle(),
+            Ok(((128 + 2048) / 2 - 128) << 4)
+        );
+    }
+
+    #[test]
+    fn too_short_a_high_is_out_of_range() {
+        let pin = Cell::new(false);
+        let clock = MockClock::new();
+        let mut sensor = MagneticPWM::new(MockPin(&pin), &clock, AS5600_PWM_CONFIG);
+        // a little short of the init period is still angle zero.
+        send(&mut sensor, &pin, &clock, 120, 4351 - 120);
+        assert_eq!(sensor.get_mechanical_angle(), Ok(0));
+        // well short of it is not a frame.
+        send(&mut sensor, &pin, &clock, 50, 4351 - 50);
+        assert_eq!(sensor.get_mechanical_angle(), Err(SensorError::OutOfRange));
+    }
+
+    #[test]
+    fn stopped_output_is_a_timeout() {
+        let pin = Cell::new(false);
+        let clock = MockClock::new();
+        let mut sensor = MagneticPWM::new(MockPin(&pin), &clock, AS5600_PWM_CONFIG);
+        send(&mut sensor, &pin, &clock, 128 + 2048, 4351 - 128 - 2048);
+        assert_eq!(sensor.get_mechanical_angle(), Ok(0x8000));
+        clock.advance_us(2 * 4351);
+        assert_eq!(sensor.get_mechanical_angle(), Ok(0x8000));
+        clock.advance_us(1);
+        assert_eq!(sensor.get_mechanical_angle(), Err(SensorError::Timeout));
+    }
+}
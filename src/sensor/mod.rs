@@ -1,26 +1,162 @@
-use defmt::*;
-
 use core::f32::consts;
 use micromath::F32;
 
-use rp2040_hal::timer::Instant;
-use rp2040_hal::Timer;
+use crate::common::clock::{Clock, Instant};
+use crate::common::em;
+use correction::SensorCorrection;
+use estimator::{AngleEstimator, ExponentialFilter};
 
+pub mod correction;
+pub mod current_inline;
+pub mod encoder;
+pub mod estimator;
+pub mod flux_observer;
+pub mod hall;
 pub mod magnetic_i2c;
+pub mod magnetic_pwm;
+pub mod magnetic_spi;
 
 // Sensor is something that returns the rotor angle sensed by something relative to somewhere.
-// TODO: current sensing.
 
 pub trait RotarySensor {
-    fn get_mechanical_angle(&mut self) -> Result<u16, embedded_hal::i2c::ErrorKind>;
+    fn get_mechanical_angle(&mut self) -> Result<u16, SensorError>;
+}
+
+// Why a reading failed, whatever the sensor and however it is connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorError {
+    // the bus or the pins could not be read.
+    Bus,
+    // the reading came in, but its parity does not check out.
+    Parity,
+    // the sensor says the magnet is missing, too far or too close.
+    MagnetMissing,
+    // the reading is outside of what the sensor can give.
+    OutOfRange,
+    // no new reading came in time.
+    Timeout,
+    // the sensor is in a state it should never be in, or flags its own reading as bad.
+    InvalidState,
+}
+
+// How many readings failed for each reason.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SensorErrorCounts {
+    pub bus: u32,
+    pub parity: u32,
+    pub magnet_missing: u32,
+    pub out_of_range: u32,
+    pub timeout: u32,
+    pub invalid_state: u32,
+}
+
+impl SensorErrorCounts {
+    pub fn count(&mut self, error: SensorError) {
+        let count = match error {
+            SensorError::Bus => &mut self.bus,
+            SensorError::Parity => &mut self.parity,
+            SensorError::MagnetMissing => &mut self.magnet_missing,
+            SensorError::OutOfRange => &mut self.out_of_range,
+            SensorError::Timeout => &mut self.timeout,
+            SensorError::InvalidState => &mut self.invalid_state,
+        };
+        *count = count.saturating_add(1);
+    }
+
+    pub fn total(&self) -> u32 {
+        self.bus
+            .saturating_add(self.parity)
+            .saturating_add(self.magnet_missing)
+            .saturating_add(self.out_of_range)
+            .saturating_add(self.timeout)
+            .saturating_add(self.invalid_state)
+    }
+}
+
+// Stands in for the rotary sensor of a motor that does not have one, it can never be made.
+pub enum NoRotarySensor {}
+
+impl RotarySensor for NoRotarySensor {
+    fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
+        match *self {}
+    }
+}
+
+// Current sensor is something that returns the current flowing into each phase of the motor.
+
+pub trait CurrentSensor {
+    fn get_phase_currents(&mut self) -> Result<em::Iabc, CurrentSenseError>;
+}
+
+// Stands in for the current sensor of a motor that does not have one, it can never be made.
+pub enum NoCurrentSensor {}
+
+impl CurrentSensor for NoCurrentSensor {
+    fn get_phase_currents(&mut self) -> Result<em::Iabc, CurrentSenseError> {
+        match *self {}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CurrentSenseError {
+    // the adc could not give a reading.
+    Adc,
+    // the zero current reading is too far from where it should be,
+    // either the driver is still on or the amplifier is not working.
+    OffsetOutOfRange,
+}
+
+// Tracker is something that knows where the rotor is and how fast it goes, whether it has a sensor or not.
+
+pub trait RotorTracker {
+    // called once at the start of every foc loop.
+    fn update(&mut self);
+    // the voltage applied and the current measured in the last foc loop.
+    // trackers without a sensor work out the angle from these, the rest can ignore them.
+    fn observe(&mut self, _v_abc: &em::Vabc, _i_abc: &em::Iabc) {}
+
+    // return the number of radians with respect to the selected origin and direction
+    fn get_rads(&self) -> f32;
+    // return the angular velocity with respect to the selected direction
+    fn get_rads_per_s(&self) -> f32;
+
+    // return the number of turns, whole and fractional, with respect to the selected origin and direction
+    fn get_revs(&self) -> f32 {
+        self.get_rads() / consts::TAU
+    }
+
+    // return the fraction of a turn with respect to the selected origin and direction
+    fn get_fract(&self) -> f32 {
+        self.get_revs() % 1.0
+    }
+}
+
+// Also stands in for the tracker of a motor that has none, so a motor can be made without one.
+impl RotorTracker for NoRotarySensor {
+    fn update(&mut self) {
+        match *self {}
+    }
+
+    fn get_rads(&self) -> f32 {
+        match *self {}
+    }
+
+    fn get_rads_per_s(&self) -> f32 {
+        match *self {}
+    }
 }
 
 // RotorTracker is a wrapper around sensors to track the number of turns, fraction of turns and an angular speed.
-pub struct RotorState<'a, RSensor: RotarySensor> {
+pub struct RotorState<'a, RSensor: RotarySensor, T: Clock, E: AngleEstimator = ExponentialFilter> {
     // source of rotor information
     sensor: RSensor,
     // source of temporal information
-    timer: &'a Timer,
+    clock: &'a T,
+
+    // last reading as it came from the sensor, before correction
+    reading: u16,
+    // taken off every reading, for errors that repeat every revolution
+    correction: Option<SensorCorrection>,
 
     // number of full revolutions, rounded to negative infinity
     full_revs: i16,
@@ -29,50 +165,116 @@ pub struct RotorState<'a, RSensor: RotarySensor> {
     // last updated
     prior_update: Instant,
 
-    // angular position
+    // angular position as read
     rads: f32,
-    // angular velocity
-    rads_per_s: f32,
+    // filtered position, velocity and acceleration from the readings
+    estimator: E,
 
     // modification to the return value
     is_correct_direction: bool,
     //
     reading_to_origin: f32,
+
+    // failed readings since made or last reset
+    errors: SensorErrorCounts,
 }
 
-impl<'a, RSensor: RotarySensor> RotorState<'a, RSensor> {
-    pub fn new(timer: &'a Timer, mut sensor: RSensor) -> Self {
+impl<'a, RSensor: RotarySensor, T: Clock> RotorState<'a, RSensor, T> {
+    pub fn new(clock: &'a T, sensor: RSensor) -> Self {
+        Self::with_estimator(clock, sensor, ExponentialFilter::default())
+    }
+}
+
+impl<'a, RSensor: RotarySensor, T: Clock, E: AngleEstimator> RotorState<'a, RSensor, T, E> {
+    pub fn with_estimator(clock: &'a T, mut sensor: RSensor, mut estimator: E) -> Self {
         let initial_reading;
-        let now: fugit::Instant<u64, 1, 1000000>;
+        let now: Instant;
+        let mut errors = SensorErrorCounts::default();
         loop {
+            // time stamp before reading, the same as `update`.
+            let attempt = clock.now();
             let result = sensor.get_mechanical_angle();
             match result {
                 Ok(i) => {
                     initial_reading = i;
-                    now = timer.get_counter();
+                    now = attempt;
                     break;
                 }
-                Err(_) => {
+                Err(error) => {
+                    errors.count(error);
                     info!("angle reading initialization failed");
                 }
             };
         }
+        let rads = consts::TAU * initial_reading as f32 / 65536.0;
+        estimator.reset(rads);
         RotorState {
-            timer,
+            clock,
             sensor,
 
+            reading: initial_reading,
+            correction: None,
+
             full_revs: 0,
             fractions: initial_reading,
             prior_update: now,
 
-            rads: initial_reading as f32 / 65535.0,
-            rads_per_s: 0.0,
+            rads,
+            estimator,
 
             is_correct_direction: true,
             reading_to_origin: 0.0,
+
+            errors,
         }
     }
 
+    // back to the reading as it is, in the direction it counts.
+    pub fn reset_return_mapping(&mut self) {
+        self.is_correct_direction = true;
+        self.reading_to_origin = 0.0;
+    }
+
+    pub fn get_return_mapping(&self) -> (bool, f32) {
+        (self.is_correct_direction, self.reading_to_origin)
+    }
+
+    // the last reading as it came from the sensor.
+    pub fn get_reading(&self) -> u16 {
+        self.reading
+    }
+
+    // Takes effect from the next reading.
+    pub fn set_correction(&mut self, correction: Option<SensorCorrection>) {
+        self.correction = correction;
+    }
+
+    pub fn get_correction(&self) -> Option<&SensorCorrection> {
+        self.correction.as_ref()
+    }
+
+    pub fn get_estimator(&self) -> &E {
+        &self.estimator
+    }
+
+    // angular acceleration with respect to the selected direction, if the estimator tracks it
+    pub fn get_rads_per_s2(&self) -> Option<f32> {
+        let rads_per_s2 = self.estimator.get_rads_per_s2()?;
+        if self.is_correct_direction {
+            Some(rads_per_s2)
+        } else {
+            Some(-rads_per_s2)
+        }
+    }
+
+    pub fn get_error_counts(&self) -> &SensorErrorCounts {
+        &self.errors
+    }
+
+    pub fn reset_error_counts(&mut self) {
+        self.errors = SensorErrorCounts::default();
+    }
+
     pub fn set_return_mapping(&mut self, is_correct_direction: bool, reading_to_origin: f32) {
         // the following logic combines the existing transformation and the new transformation into a new transformation.
         if self.is_correct_direction {
@@ -82,16 +284,23 @@ impl<'a, RSensor: RotarySensor> RotorState<'a, RSensor> {
         }
         self.is_correct_direction ^= !is_correct_direction;
     }
+}
 
-    pub fn update(&mut self) {
-        let now: rp2040_hal::fugit::Instant<u64, 1, 1000000> = self.timer.get_counter();
+impl<RSensor: RotarySensor, T: Clock, E: AngleEstimator> RotorTracker
+    for RotorState<'_, RSensor, T, E>
+{
+    fn update(&mut self) {
+        let now = self.clock.now();
         let delta_s = ((now - self.prior_update).to_micros() as f32) / 1000000.0;
 
         let potential_reading = self.sensor.get_mechanical_angle();
         match potential_reading {
-            Ok(rotor_angle) => {
-                let prior_rads = self.rads;
-
+            Ok(reading) => {
+                self.reading = reading;
+                let rotor_angle = match self.correction.as_ref() {
+                    Some(correction) => correction.apply(reading),
+                    None => reading,
+                };
                 let quadrant: u16 = 4 * (rotor_angle / 16384) + self.fractions / 16384;
 
                 match quadrant {
@@ -114,13 +323,13 @@ impl<'a, RSensor: RotarySensor> RotorState<'a, RSensor> {
                 self.rads =
                     consts::TAU * (self.full_revs as f32 + (self.fractions as f32 / 65536.0));
 
-                // quick exponential filter to get
-                self.rads_per_s =
-                    0.99 * self.rads_per_s + 0.01 * (self.rads - prior_rads) / delta_s;
+                self.estimator.update(self.rads, delta_s);
             }
-            Err(_) => {
+            Err(error) => {
+                self.errors.count(error);
                 // still update, just based on the prior results.
-                self.rads += self.rads_per_s * delta_s;
+                self.estimator.predict(delta_s);
+                self.rads = self.estimator.get_rads();
                 let revs = F32(self.rads / consts::TAU);
                 self.full_revs = revs.floor().0 as i16;
                 self.fractions = (65536.0 * (revs.0 - revs.floor().0)) as u16;
@@ -130,30 +339,22 @@ impl<'a, RSensor: RotarySensor> RotorState<'a, RSensor> {
     }
 
     // return the number of radians with respect to the selected origin and direction
-    pub fn get_rads(&self) -> f32 {
+    fn get_rads(&self) -> f32 {
+        let rads = self.estimator.get_rads();
         if self.is_correct_direction {
-            self.rads - self.reading_to_origin
+            rads - self.reading_to_origin
         } else {
-            -(self.rads - self.reading_to_origin)
+            -(rads - self.reading_to_origin)
         }
     }
 
     // return the angular velocity with respect to the selected direction
-    pub fn get_rads_per_s(&self) -> f32 {
+    fn get_rads_per_s(&self) -> f32 {
+        let rads_per_s = self.estimator.get_rads_per_s();
         if self.is_correct_direction {
-            self.rads_per_s
+            rads_per_s
         } else {
-            -self.rads_per_s
+            -rads_per_s
         }
     }
-
-    // return the number of turns, whole and fractional, with respect to the selected origin and direction
-    pub fn get_revs(&self) -> f32 {
-        self.get_rads() / consts::TAU
-    }
-
-    // return the fraction of a turn with respect to the selected origin and direction
-    pub fn get_fract(&self) -> f32 {
-        self.get_revs() % 1.0
-    }
 }
@@ -0,0 +1,1145 @@
+// A simulated bldc motor to close the loop without any hardware.
+// The plant sits behind the driver and the sensor, so the control code cannot tell the difference.
+//
+// Conventions follow the rest of the crate:
+//      the magnet flux of phase x is flux_linkage * sin(electrical_angle - phase_x),
+//      so q voltage from `Vqd::inverse_parks_transformation` produces torque
+//      and d voltage pulls the rotor to the commanded electrical angle.
+
+use core::cell::RefCell;
+use core::f32::consts;
+use micromath::F32;
+
+use crate::common::clock::{Clock, MockClock};
+use crate::common::em;
+use crate::driver::BLDCDriver;
+use crate::sensor::current_inline::{InlineCurrentSenseConfig, Phase, PhaseAdc};
+use crate::sensor::{CurrentSenseError, RotarySensor, SensorError};
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+
+// Physical description of the simulated motor and what is attached to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlantParameters {
+    // number of electrical cycles per mechanical cycle
+    pub pole_pairs: u8,
+    // rpm per volt, sets the back emf and torque constant.
+    pub kv: f32,
+    // ohm, per phase
+    pub phase_resistance: f32,
+    // henry, per phase
+    pub phase_inductance: f32,
+    // kg m^2, rotor and whatever is attached.
+    pub inertia: f32,
+    // N m s / rad
+    pub viscous_friction: f32,
+    // N m, opposes motion and holds the rotor still below this torque.
+    pub coulomb_friction: f32,
+    // N m, amplitude of the sinusoidal cogging torque
+    pub cogging_torque: f32,
+    // number of cogging cycles per mechanical revolution, usually lcm(slots, poles).
+    pub cogging_periods: u16,
+    // N m, constant external torque against the positive direction.
+    pub load_torque: f32,
+    // electrical angle of the rotor when the mechanical angle is 0.
+    pub electrical_offset: f32,
+}
+
+// A small gimbal style motor, similar to the one on the test bench.
+pub const GIMBAL_MOTOR: PlantParameters = PlantParameters {
+    pole_pairs: 7,
+    kv: 100.0,
+    phase_resistance: 5.0,
+    phase_inductance: 0.002,
+    inertia: 0.000_02,
+    viscous_friction: 0.000_01,
+    coulomb_friction: 0.000_5,
+    cogging_torque: 0.000_5,
+    cogging_periods: 84,
+    load_torque: 0.0,
+    electrical_offset: 0.0,
+};
+
+// State of the motor at some instant in simulated time.
+pub struct MotorPlant {
+    pub parameters: PlantParameters,
+
+    // simulated time in micro seconds
+    time_us: u64,
+    // terminal voltage of each phase relative to ground, held until changed.
+    voltages: [f32; 3],
+    // phase currents, positive flowing into the motor.
+    currents: [f32; 3],
+    // mechanical angle, not wrapped.
+    rads: f32,
+    // mechanical angular velocity
+    rads_per_s: f32,
+}
+
+impl MotorPlant {
+    // longest time step taken by the integrator.
+    const MAX_STEP_US: u64 = 20;
+
+    pub fn new(parameters: PlantParameters) -> Self {
+        MotorPlant {
+            parameters,
+            time_us: 0,
+            voltages: [0.0; 3],
+            currents: [0.0; 3],
+            rads: 0.0,
+            rads_per_s: 0.0,
+        }
+    }
+
+    // volt seconds per electrical radian, peak per phase.
+    pub fn flux_linkage(&self) -> f32 {
+        60.0 / (consts::TAU * 1.732_050_8 * self.parameters.kv * self.parameters.pole_pairs as f32)
+    }
+
+    pub fn get_time_us(&self) -> u64 {
+        self.time_us
+    }
+
+    pub fn get_rads(&self) -> f32 {
+        self.rads
+    }
+
+    pub fn get_rads_per_s(&self) -> f32 {
+        self.rads_per_s
+    }
+
+    pub fn get_electrical_angle(&self) -> f32 {
+        self.rads * self.parameters.pole_pairs as f32 + self.parameters.electrical_offset
+    }
+
+    pub fn get_currents(&self) -> em::Iabc {
+        em::Iabc {
+            a: self.currents[0],
+            b: self.currents[1],
+            c: self.currents[2],
+        }
+    }
+
+    // torque produced by the phase currents alone.
+    pub fn get_electrical_torque(&self) -> f32 {
+        let electrical_angle = self.get_electrical_angle();
+        let mut torque = 0.0;
+        for (phase, current) in self.currents.iter().enumerate() {
+            let (_, c) = F32(electrical_angle - Self::phase_angle(phase)).sin_cos();
+            torque += current * c.0;
+        }
+        torque * self.parameters.pole_pairs as f32 * self.flux_linkage()
+    }
+
+    // terminal voltages as last set.
+    pub fn get_phase_voltages(&self) -> em::Vabc {
+        em::Vabc {
+            a: self.voltages[0],
+            b: self.voltages[1],
+            c: self.voltages[2],
+        }
+    }
+
+    // place the rotor somewhere, at rest.
+    pub fn set_rads(&mut self, rads: f32) {
+        self.rads = rads;
+        self.rads_per_s = 0.0;
+    }
+
+    pub fn set_load_torque(&mut self, load_torque: f32) {
+        self.parameters.load_torque = load_torque;
+    }
+
+    // terminal voltages, they are held until the next call.
+    pub fn set_phase_voltages(&mut self, voltages: em::Vabc) {
+        self.voltages = [voltages.a, voltages.b, voltages.c];
+    }
+
+    // integrate up to the given time, the plant never goes backward in time.
+    pub fn advance_to(&mut self, time_us: u64) {
+        while self.time_us < time_us {
+            let step_us = (time_us - self.time_us).min(Self::MAX_STEP_US);
+            self.step(step_us as f32 / 1_000_000.0);
+            self.time_us += step_us;
+        }
+    }
+
+    fn phase_angle(phase: usize) -> f32 {
+        // matches the order used by the park transformations.
+        match phase {
+            0 => 0.0,
+            1 => consts::TAU / 3.0,
+            _ => -consts::TAU / 3.0,
+        }
+    }
+
+    fn step(&mut self, dt: f32) {
+        let p = &self.parameters;
+        let pole_pairs = p.pole_pairs as f32;
+        let electrical_angle = self.get_electrical_angle();
+        let electrical_speed = self.rads_per_s * pole_pairs;
+        let flux_linkage = self.flux_linkage();
+
+        // back emf of each phase
+        let mut back_emf = [0.0f32; 3];
+        for (phase, emf) in back_emf.iter_mut().enumerate() {
+            let (_, c) = F32(electrical_angle - Self::phase_angle(phase)).sin_cos();
+            *emf = flux_linkage * electrical_speed * c.0;
+        }
+
+        // star connected, the neutral floats to wherever keeps the currents summing to 0.
+        let neutral = (self.voltages.iter().sum::<f32>() - back_emf.iter().sum::<f32>()) / 3.0;
+
+        // exact solution of the rl circuit over the step, stable for any step size.
+        let decay = 1.0 - F32(-p.phase_resistance * dt / p.phase_inductance).exp().0;
+        for ((current, voltage), emf) in self.currents.iter_mut().zip(self.voltages).zip(back_emf) {
+            let steady_state = (voltage - neutral - emf) / p.phase_resistance;
+            *current += (steady_state - *current) * decay;
+        }
+
+        // mechanical side
+        let cogging = p.cogging_torque * F32(self.rads * p.cogging_periods as f32).sin().0;
+        let drive = self.get_electrical_torque() - cogging - p.load_torque;
+        let friction = p.viscous_friction * self.rads_per_s;
+
+        let rads_per_s = if self.rads_per_s == 0.0 && F32(drive).abs().0 <= p.coulomb_friction {
+            // stiction holds the rotor.
+            0.0
+        } else {
+            let coulomb = if self.rads_per_s > 0.0 {
+                p.coulomb_friction
+            } else if self.rads_per_s < 0.0 {
+                -p.coulomb_friction
+            } else if drive > 0.0 {
+                p.coulomb_friction
+            } else {
+                -p.coulomb_friction
+            };
+            let next = self.rads_per_s + (drive - friction - coulomb) / p.inertia * dt;
+            // friction can stop the rotor but not reverse it.
+            if self.rads_per_s != 0.0 && next * self.rads_per_s < 0.0 {
+                0.0
+            } else {
+                next
+            }
+        };
+
+        self.rads += 0.5 * (self.rads_per_s + rads_per_s) * dt;
+        self.rads_per_s = rads_per_s;
+    }
+}
+
+// The plant and the clock that drives it.
+// Drivers and sensors borrow it, so they all see the same motor.
+pub struct Simulation {
+    pub plant: RefCell<MotorPlant>,
+    pub clock: MockClock,
+}
+
+impl Simulation {
+    pub fn new(parameters: PlantParameters) -> Self {
+        Simulation {
+            plant: RefCell::new(MotorPlant::new(parameters)),
+            clock: MockClock::new(),
+        }
+    }
+
+    // bring the plant up to the current simulated time.
+    pub fn sync(&self) {
+        self.plant.borrow_mut().advance_to(self.clock.now().ticks());
+    }
+
+    pub fn driver(&self, vdc: f32) -> SimulatedDriver<'_> {
+        SimulatedDriver {
+            sim: self,
+            vdc,
+            update_period_us: 0,
+        }
+    }
+
+    // the amplifiers of an inline current sensor, with some error in their zero current output.
+    pub fn adc(&self, config: InlineCurrentSenseConfig, offset_voltage: f32) -> SimulatedAdc<'_> {
+        SimulatedAdc {
+            sim: self,
+            config,
+            offset_voltage,
+        }
+    }
+
+    // every reading of the sensor takes one control period of simulated time.
+    pub fn sensor(&self, sample_period_us: u64) -> SimulatedSensor<'_> {
+        SimulatedSensor {
+            sim: self,
+            sample_period_us,
+            bit_resolution: 16,
+            is_reversed: false,
+            offset: 0.0,
+            eccentricity: 0.0,
+        }
+    }
+}
+
+// Behaves like `BLDCDriver3PWM` with perfect switches.
+pub struct SimulatedDriver<'a> {
+    sim: &'a Simulation,
+    pub vdc: f32,
+    // simulated time taken per voltage update.
+    // without a rotary sensor nothing else moves the clock, so set this for sensorless control.
+    pub update_period_us: u64,
+}
+
+impl BLDCDriver for SimulatedDriver<'_> {
+    fn get_voltage_limit(&self) -> f32 {
+        // root 3 for 3 phases.
+        self.vdc / 1.732
+    }
+
+    fn set_srf_voltage(&mut self, v_srf: em::Vabc) {
+        let v_srf = v_srf.limit(self.get_voltage_limit());
+        let minimum_v = v_srf.a.min(v_srf.b).min(v_srf.c);
+        let clamp = |v: f32| (v - minimum_v).clamp(0.0, self.vdc);
+
+        self.sim.sync();
+        self.sim.plant.borrow_mut().set_phase_voltages(em::Vabc {
+            a: clamp(v_srf.a),
+            b: clamp(v_srf.b),
+            c: clamp(v_srf.c),
+        });
+        self.sim.clock.advance_us(self.update_period_us);
+    }
+
+    fn set_rrf_voltage(&mut self, v_rrf: em::Vqd, rotor_angle_rads: f32) {
+        let v_srf = v_rrf
+            .limit(self.get_voltage_limit())
+            .inverse_parks_transformation(rotor_angle_rads);
+        self.set_srf_voltage(v_srf);
+    }
+
+    fn off(&mut self) {
+        self.sim.sync();
+        self.sim.plant.borrow_mut().set_phase_voltages(em::Vabc {
+            a: 0.0,
+            b: 0.0,
+            c: 0.0,
+        });
+        self.sim.clock.advance_us(self.update_period_us);
+    }
+}
+
+// Behaves like a magnetic sensor on the rotor shaft.
+pub struct SimulatedSensor<'a> {
+    sim: &'a Simulation,
+    // simulated time taken per reading.
+    pub sample_period_us: u64,
+    // resolution of the simulated sensor, the reading is still scaled to 16 bits.
+    pub bit_resolution: u8,
+    // mounting of the sensor relative to the rotor.
+    pub is_reversed: bool,
+    pub offset: f32,
+    // rad, amplitude of the once per revolution error of a magnet off centre.
+    pub eccentricity: f32,
+}
+
+impl RotarySensor for SimulatedSensor<'_> {
+    fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
+        self.sim.clock.advance_us(self.sample_period_us);
+        self.sim.sync();
+
+        let rads = self.sim.plant.borrow().get_rads();
+        let rads = if self.is_reversed { -rads } else { rads } + self.offset;
+        let rads = rads + self.eccentricity * F32(rads).sin().0;
+        let revs = F32(rads / consts::TAU);
+        let reading = (65536.0 * (revs.0 - revs.floor().0)) as u32 & 0xffff;
+        let mask = !((1u32 << (16 - self.bit_resolution)) - 1);
+        Ok((reading & mask) as u16)
+    }
+}
+
+// Behaves like the adc behind an inline current sensor.
+// Readings take no simulated time, so they are in step with the angle reading before them.
+pub struct SimulatedAdc<'a> {
+    sim: &'a Simulation,
+    pub config: InlineCurrentSenseConfig,
+    // volt, added to the half scale output of the amplifiers.
+    pub offset_voltage: f32,
+}
+
+impl PhaseAdc for SimulatedAdc<'_> {
+    fn read(&mut self, phase: Phase) -> Result<u16, CurrentSenseError> {
+        self.sim.sync();
+
+        let currents = self.sim.plant.borrow().get_currents();
+        let current = match phase {
+            Phase::A => currents.a,
+            Phase::B => currents.b,
+            Phase::C => currents.c,
+        };
+        let full_scale = ((1u32 << self.config.adc_bit_resolution) - 1) as f32;
+        let volts = self.config.adc_reference_voltage / 2.0
+            + self.offset_voltage
+            + current * self.config.shunt_resistance * self.config.amplifier_gain;
+        let counts = volts / self.config.adc_reference_voltage * full_scale;
+        Ok(counts.clamp(0.0, full_scale) as u16)
+    }
+}
+
+// Behaves like nor flash, erased to all ones and written by clearing bits.
+// Writes over bits that are not erased are not caught, they give what the chip would give.
+pub struct SimulatedFlash<const SIZE: usize> {
+    pub memory: [u8; SIZE],
+    // every read fails, like a bus fault would.
+    pub is_read_failing: bool,
+}
+
+impl<const SIZE: usize> SimulatedFlash<SIZE> {
+    pub const fn new() -> Self {
+        SimulatedFlash {
+            memory: [0xff; SIZE],
+            is_read_failing: false,
+        }
+    }
+
+    fn check(&self, offset: u32, length: usize, alignment: usize) -> Result<(), NorFlashErrorKind> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(alignment) || !length.is_multiple_of(alignment) {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        if offset + length > SIZE {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Ok(())
+    }
+}
+
+impl<const SIZE: usize> Default for SimulatedFlash<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> ErrorType for SimulatedFlash<SIZE> {
+    type Error = NorFlashErrorKind;
+}
+
+// sizes of the rp2040's qspi flash.
+impl<const SIZE: usize> ReadNorFlash for SimulatedFlash<SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        if self.is_read_failing {
+            return Err(NorFlashErrorKind::Other);
+        }
+        self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.memory[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize> NorFlash for SimulatedFlash<SIZE> {
+    const WRITE_SIZE: usize = 256;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if to < from {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        self.check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+        self.memory[from as usize..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        let offset = offset as usize;
+        for (cell, byte) in self.memory[offset..].iter_mut().zip(bytes) {
+            *cell &= *byte;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::autotune::{RelayConfig, TuningRule};
+    use crate::bldc_motor::{BLDCMotor, BLDCMotorSpecification, CalibrationResult, TunedLoop};
+    use crate::cogging::CoggingCompensation;
+    use crate::current_control::CurrentController;
+    use crate::motion::{MotionProfile, TrapezoidalProfile};
+    use crate::pid::{PIDGains, PID};
+    use crate::sensor::current_inline::{InlineCurrentSensor, INA240A2_10MOHM_CONFIG};
+    use crate::sensor::flux_observer::{FluxObserver, DEFAULT_FLUX_OBSERVER_CONFIG};
+    use crate::sensor::{RotorState, RotorTracker};
+    use crate::storage::{self, StorageError};
+    use crate::{ControlMode, FOCMotor};
+
+    type SimulatedMotor<'a> = BLDCMotor<
+        'a,
+        SimulatedDriver<'a>,
+        RotorState<'a, SimulatedSensor<'a>, MockClock>,
+        MockClock,
+    >;
+
+    fn new_motor<'a>(
+        sim: &'a Simulation,
+        sensor: SimulatedSensor<'a>,
+        pole_pairs: u8,
+    ) -> SimulatedMotor<'a> {
+        BLDCMotor::new(
+            BLDCMotorSpecification {
+                pole_pairs,
+                kv: 100,
+                phase_resistance: 5.0,
+                phase_inductance: 0.002,
+            },
+            Some(RotorState::new(&sim.clock, sensor)),
+            sim.driver(12.0),
+            PID::new(&sim.clock, 20.0, 0.0, 0.0, 0.0),
+            PID::new(&sim.clock, 0.05, 1.0, 0.0, 0.0),
+        )
+    }
+
+    #[test]
+    fn calibrate_then_goto() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        // wrong on purpose, calibration finds the pole pairs.
+        let mut motor = new_motor(&sim, sim.sensor(100), 1);
+
+        let calibration = motor.calibrate_rotary_sensor().unwrap();
+        assert_eq!(calibration.pole_pairs, 7);
+        assert!(!calibration.is_reversed);
+
+        for target in [3.0, -2.0, 10.0] {
+            motor.goto_blocking(target);
+            let rads = motor.angle.as_ref().unwrap().get_rads();
+            assert!((rads - target).abs() < 0.01, "{rads} for {target}");
+            // the plant agrees with the sensor.
+            let plant_rads = sim.plant.borrow().get_rads();
+            assert!(
+                (rads - plant_rads).abs() < 0.01,
+                "{rads} against {plant_rads}"
+            );
+        }
+    }
+
+    fn calibrated_motor(sim: &Simulation) -> SimulatedMotor<'_> {
+        let mut motor = new_motor(sim, sim.sensor(100), 7);
+        motor.calibrate_rotary_sensor().unwrap();
+        motor
+    }
+
+    // the q and d voltage the plant is seeing.
+    fn applied_vqd(sim: &Simulation) -> em::Vqd {
+        let plant = sim.plant.borrow();
+        plant
+            .get_phase_voltages()
+            .parks_transformation(plant.get_electrical_angle())
+    }
+
+    #[test]
+    fn velocity_mode() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = calibrated_motor(&sim);
+        motor.set_control_mode(ControlMode::Velocity);
+        for target in [10.0, -5.0] {
+            motor.set_target(target);
+            for _ in 0..10_000 {
+                motor.foc_loop();
+            }
+            let rads_per_s = sim.plant.borrow().get_rads_per_s();
+            assert!(
+                (rads_per_s - target).abs() < 0.5,
+                "{rads_per_s} for {target}"
+            );
+        }
+    }
+
+    #[test]
+    fn angle_mode() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = calibrated_motor(&sim);
+        motor.set_control_mode(ControlMode::Angle);
+        // starts where the rotor is.
+        let start = sim.plant.borrow().get_rads();
+        motor.foc_loop();
+        assert!((sim.plant.borrow().get_rads() - start).abs() < 0.01);
+
+        let target = start + 2.0;
+        motor.set_target(target);
+        for _ in 0..20_000 {
+            motor.foc_loop();
+        }
+        let rads = sim.plant.borrow().get_rads();
+        assert!((rads - target).abs() < 0.01, "{rads} for {target}");
+    }
+
+    #[test]
+    fn voltage_torque_mode() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = calibrated_motor(&sim);
+        motor.set_control_mode(ControlMode::VoltageTorque);
+        for target in [1.0, -1.0] {
+            motor.set_target(target);
+            for _ in 0..10_000 {
+                motor.foc_loop();
+            }
+            let vqd = applied_vqd(&sim);
+            assert!((vqd.q - target).abs() < 0.05, "{} for {target}", vqd.q);
+            assert!(vqd.d.abs() < 0.05, "{}", vqd.d);
+            // spinning the way it is pushed.
+            let rads_per_s = sim.plant.borrow().get_rads_per_s();
+            assert!(rads_per_s * target > 1.0, "{rads_per_s} for {target}");
+        }
+    }
+
+    #[test]
+    fn current_torque_mode() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = calibrated_motor(&sim);
+        let mut current_sensor = InlineCurrentSensor::new(
+            sim.adc(INA240A2_10MOHM_CONFIG, 0.01),
+            INA240A2_10MOHM_CONFIG,
+        );
+        // let the current from calibrating die away first.
+        motor.driver.off();
+        sim.clock.advance_us(10_000);
+        sim.sync();
+        current_sensor.calibrate_offsets(&mut motor.driver).unwrap();
+        let current_controller =
+            CurrentController::from_specification(&sim.clock, &motor.specification, 2000.0, 1.0);
+        let mut motor = motor.with_current_sensing(current_sensor, current_controller);
+
+        motor.set_control_mode(ControlMode::CurrentTorque);
+        motor.set_target(0.1);
+        // settled, and before the back emf takes up the voltage.
+        let mut q_sum = 0.0;
+        for i in 0..200 {
+            motor.foc_loop();
+            if i >= 50 {
+                let plant = sim.plant.borrow();
+                let iqd = plant
+                    .get_currents()
+                    .parks_transformation(plant.get_electrical_angle());
+                q_sum += iqd.q;
+            }
+        }
+        let q = q_sum / 150.0;
+        // the current is sampled once a period, in between it moves a little.
+        assert!((q - 0.1).abs() < 0.02, "{q}");
+        assert!(sim.plant.borrow().get_rads_per_s() > 1.0);
+    }
+
+    #[test]
+    fn each_stage_is_limited() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = calibrated_motor(&sim);
+        motor.velocity_limit = 5.0;
+        motor.voltage_limit = 1.0;
+        motor.set_control_mode(ControlMode::Angle);
+        let target = sim.plant.borrow().get_rads() + 10.0;
+        motor.set_target(target);
+
+        let mut fastest: f32 = 0.0;
+        for _ in 0..40_000 {
+            motor.foc_loop();
+            fastest = fastest.max(sim.plant.borrow().get_rads_per_s().abs());
+            let vqd = applied_vqd(&sim);
+            let magnitude = F32(vqd.q * vqd.q + vqd.d * vqd.d).sqrt().0;
+            // micromath's square root is a few percent off.
+            assert!(magnitude < 1.0 * 1.07, "{magnitude}");
+        }
+        assert!(fastest < 5.0 * 1.1, "{fastest}");
+        let rads = sim.plant.borrow().get_rads();
+        assert!((rads - target).abs() < 0.01, "{rads} for {target}");
+    }
+
+    #[test]
+    fn outer_loops_run_at_their_own_rate() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = calibrated_motor(&sim);
+        motor.velocity_loop_divider = 4;
+        motor.set_control_mode(ControlMode::Velocity);
+        motor.set_target(5.0);
+        // the q voltage only moves when the velocity loop runs, on the first loop of every 4.
+        let mut prior_q = applied_vqd(&sim).q;
+        for i in 0..400 {
+            motor.foc_loop();
+            let q = applied_vqd(&sim).q;
+            if i % 4 != 0 {
+                assert!((q - prior_q).abs() < 1e-3, "{q} after {prior_q} at {i}");
+            }
+            prior_q = q;
+        }
+
+        // and the cascade still gets there with the angle loop at a tenth of the rate.
+        motor.angle_loop_divider = 10;
+        motor.velocity_loop_divider = 2;
+        motor.set_control_mode(ControlMode::Angle);
+        let target = sim.plant.borrow().get_rads() + 2.0;
+        motor.set_target(target);
+        for _ in 0..20_000 {
+            motor.foc_loop();
+        }
+        let rads = sim.plant.borrow().get_rads();
+        assert!((rads - target).abs() < 0.01, "{rads} for {target}");
+    }
+
+    #[test]
+    fn open_loop_without_a_sensor() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut driver = sim.driver(12.0);
+        // nothing else moves the clock, 10 kHz.
+        driver.update_period_us = 100;
+        let mut motor: SimulatedMotor<'_> = BLDCMotor::new(
+            BLDCMotorSpecification {
+                pole_pairs: 7,
+                kv: 100,
+                phase_resistance: 5.0,
+                phase_inductance: 0.002,
+            },
+            None,
+            driver,
+            PID::new(&sim.clock, 20.0, 0.0, 0.0, 0.0),
+            PID::new(&sim.clock, 0.05, 1.0, 0.0, 0.0),
+        );
+        motor.open_loop_voltage_limit = 2.0;
+
+        // the closed loop modes have nothing to go on, so the driver stays off.
+        for mode in [
+            ControlMode::Angle,
+            ControlMode::Velocity,
+            ControlMode::VoltageTorque,
+        ] {
+            motor.set_control_mode(mode);
+            motor.set_target(1.0);
+            motor.foc_loop();
+            let voltages = sim.plant.borrow().get_phase_voltages();
+            assert_eq!((voltages.a, voltages.b, voltages.c), (0.0, 0.0, 0.0));
+        }
+
+        // goto drags the rotor there instead, no faster than the velocity limit.
+        let start_us = sim.clock.now().ticks();
+        motor.goto_blocking(2.0);
+        assert_eq!(motor.get_control_mode(), ControlMode::OpenLoopAngle);
+        let seconds = (sim.clock.now().ticks() - start_us) as f32 / 1_000_000.0;
+        assert!(seconds > 2.0 / motor.velocity_limit, "{seconds}");
+        for _ in 0..5000 {
+            motor.foc_loop();
+        }
+        let rads = sim.plant.borrow().get_rads();
+        assert!((rads - 2.0).abs() < 0.02, "{rads}");
+        let vqd = applied_vqd(&sim);
+        let magnitude = F32(vqd.q * vqd.q + vqd.d * vqd.d).sqrt().0;
+        assert!((magnitude - 2.0).abs() < 2.0 * 0.07, "{magnitude}");
+
+        // and spins it at the speed asked for.
+        motor.set_control_mode(ControlMode::OpenLoopVelocity);
+        motor.set_target(10.0);
+        for _ in 0..5000 {
+            motor.foc_loop();
+        }
+        let start = sim.plant.borrow().get_rads();
+        for _ in 0..10_000 {
+            motor.foc_loop();
+        }
+        let turned = sim.plant.borrow().get_rads() - start;
+        assert!((turned - 10.0).abs() < 0.1, "{turned}");
+    }
+
+    #[test]
+    fn autotune_then_hold() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = new_motor(&sim, sim.sensor(100), 7);
+        motor.calibrate_rotary_sensor().unwrap();
+        // start from gains that would not hold anything.
+        let untuned = PIDGains {
+            kp: 0.0,
+            ki: 0.0,
+            kd: 0.0,
+        };
+        motor.velocity_pid.set_gains(&untuned);
+        motor.angle_pid.set_gains(&untuned);
+        let control_mode = motor.get_control_mode();
+
+        let velocity_relay = RelayConfig {
+            amplitude: 1.0,
+            hysteresis: 0.5,
+            max_deviation: 50.0,
+            max_duration: 5.0,
+            cycles: 4,
+        };
+        motor
+            .autotune(
+                TunedLoop::Velocity,
+                0.0,
+                &velocity_relay,
+                TuningRule::TyreusLuyben,
+            )
+            .unwrap();
+        let start = sim.plant.borrow().get_rads();
+        let angle_relay = RelayConfig {
+            amplitude: 2.0,
+            hysteresis: 0.01,
+            max_deviation: 1.0,
+            max_duration: 5.0,
+            cycles: 4,
+        };
+        motor
+            .autotune(
+                TunedLoop::Angle,
+                start,
+                &angle_relay,
+                TuningRule::NoOvershoot,
+            )
+            .unwrap();
+        assert_eq!(motor.get_control_mode(), control_mode);
+
+        let target = start + 1.0;
+        motor.goto_blocking(target);
+        // and stays there, against the cogging.
+        for _ in 0..10_000 {
+            motor.foc_loop();
+            let rads = sim.plant.borrow().get_rads();
+            assert!((rads - target).abs() < 0.01, "{rads} for {target}");
+        }
+    }
+
+    #[test]
+    fn calibration_corrects_eccentricity() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut sensor = sim.sensor(100);
+        sensor.eccentricity = 0.02;
+        let mut motor = new_motor(&sim, sensor, 7);
+        motor.calibrate_rotary_sensor().unwrap();
+
+        // rms error over a revolution, of the angle against where the rotor is put, less the mean.
+        let rms_error = |motor: &mut SimulatedMotor<'_>| {
+            let angle = motor.angle.as_mut().unwrap();
+            let start = sim.plant.borrow().get_rads();
+            let mut errors = [0.0; 256];
+            for (i, error) in errors.iter_mut().enumerate() {
+                let rads = start + i as f32 * consts::TAU / 256.0;
+                sim.plant.borrow_mut().set_rads(rads);
+                angle.update();
+                *error = angle.get_rads() - rads;
+            }
+            let mean = errors.iter().sum::<f32>() / 256.0;
+            let squares = errors.iter().map(|error| (error - mean) * (error - mean));
+            F32(squares.sum::<f32>() / 256.0).sqrt().0
+        };
+        let corrected = rms_error(&mut motor);
+        let correction = motor.angle.as_ref().unwrap().get_correction().copied();
+        assert!(correction.is_some());
+        motor.angle.as_mut().unwrap().set_correction(None);
+        let raw = rms_error(&mut motor);
+        // about 0.02 / root 2 without.
+        assert!(raw > 0.01, "{raw}");
+        assert!(corrected < 0.2 * raw, "{corrected} against {raw}");
+
+        // and the correction goes out with the calibration and into a fresh motor unchanged.
+        motor.angle.as_mut().unwrap().set_correction(correction);
+        let calibration = motor.get_config().unwrap().calibration;
+        assert_eq!(calibration.correction, correction);
+        let mut fresh = new_motor(&sim, sim.sensor(100), 1);
+        fresh.apply_calibration(&calibration);
+        assert_eq!(fresh.get_config().unwrap().calibration, calibration);
+    }
+
+    #[test]
+    fn failed_calibration_keeps_the_last_one() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut sensor = sim.sensor(100);
+        sensor.eccentricity = 0.01;
+        let mut motor = new_motor(&sim, sensor, 7);
+        let calibration = motor.calibrate_rotary_sensor().unwrap();
+        assert!(calibration.correction.is_some());
+
+        // without a supply the rotor cannot follow the field.
+        motor.driver.vdc = 0.0;
+        assert_eq!(motor.calibrate_rotary_sensor(), None);
+        assert_eq!(motor.get_config().unwrap().calibration, calibration);
+    }
+
+    // turns three times for every turn of the rotor, like a sensor on the wrong side of a gear.
+    struct GearedSensor<'a>(SimulatedSensor<'a>);
+
+    impl RotarySensor for GearedSensor<'_> {
+        fn get_mechanical_angle(&mut self) -> Result<u16, SensorError> {
+            Ok(self.0.get_mechanical_angle()?.wrapping_mul(3))
+        }
+    }
+
+    #[test]
+    fn implausible_pole_pairs_fail_calibration() {
+        // 1 / 3 of a pole pair, and 7 / 3.
+        for pole_pairs in [1, 7] {
+            let sim = Simulation::new(PlantParameters {
+                pole_pairs,
+                ..GIMBAL_MOTOR
+            });
+            let mut motor = BLDCMotor::new(
+                BLDCMotorSpecification {
+                    pole_pairs,
+                    kv: 100,
+                    phase_resistance: 5.0,
+                    phase_inductance: 0.002,
+                },
+                Some(RotorState::new(&sim.clock, GearedSensor(sim.sensor(100)))),
+                sim.driver(12.0),
+                PID::new(&sim.clock, 20.0, 0.0, 0.0, 0.0),
+                PID::new(&sim.clock, 0.05, 1.0, 0.0, 0.0),
+            );
+            assert_eq!(motor.calibrate_rotary_sensor(), None, "{pole_pairs}");
+            assert_eq!(motor.specification.pole_pairs, pole_pairs);
+        }
+    }
+
+    #[test]
+    fn calibrate_once_then_load() {
+        let mut flash = SimulatedFlash::<{ 8 * 4096 }>::new();
+        let mut table = CoggingCompensation::new();
+
+        // the first power up finds nothing and calibrates.
+        let calibration = {
+            let sim = Simulation::new(GIMBAL_MOTOR);
+            let mut sensor = sim.sensor(100);
+            sensor.offset = 1.0;
+            let mut motor = new_motor(&sim, sensor, 1);
+            let calibration = motor
+                .load_config_or_calibrate(&mut flash, 0, Some(&mut table))
+                .unwrap()
+                .unwrap();
+            assert_eq!(calibration.pole_pairs, 7);
+            // nothing was measured into the table, so it is not used or saved.
+            assert!(motor.cogging_compensation.is_none());
+            calibration
+        };
+        assert!(
+            !storage::load(&mut flash, 0, None)
+                .unwrap()
+                .has_cogging_compensation
+        );
+
+        // the next one loads it without moving the rotor.
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut sensor = sim.sensor(100);
+        sensor.offset = 1.0;
+        let mut motor = new_motor(&sim, sensor, 1);
+        assert_eq!(
+            motor.load_config_or_calibrate(&mut flash, 0, Some(&mut table)),
+            Ok(Some(calibration))
+        );
+        assert_eq!(motor.specification.pole_pairs, 7);
+        assert_eq!(sim.plant.borrow().get_rads(), 0.0);
+        // and it can be driven with it, the sensor's origin is wherever the calibration put it.
+        motor.foc_loop();
+        let start = motor.angle.as_ref().unwrap().get_rads();
+        motor.goto_blocking(start + 1.0);
+        assert!((sim.plant.borrow().get_rads() - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn flash_that_cannot_be_read_is_not_written_over() {
+        let mut flash = SimulatedFlash::<{ 8 * 4096 }>::new();
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = new_motor(&sim, sim.sensor(100), 7);
+        let config = motor.get_config().unwrap();
+        storage::save(&mut flash, 0, &config, None).unwrap();
+
+        flash.is_read_failing = true;
+        assert_eq!(
+            motor.load_config_or_calibrate(&mut flash, 0, None),
+            Err(StorageError::Flash(NorFlashErrorKind::Other))
+        );
+        // no calibration was tried, and the record is still there.
+        assert_eq!(sim.plant.borrow().get_rads(), 0.0);
+        flash.is_read_failing = false;
+        assert_eq!(storage::load(&mut flash, 0, None), Ok(config));
+    }
+
+    #[test]
+    fn config_without_a_current_loop_keeps_the_current_gains() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let config = new_motor(&sim, sim.sensor(100), 7).get_config().unwrap();
+        assert_eq!(config.current_gains, None);
+
+        let motor = new_motor(&sim, sim.sensor(100), 7);
+        let current_sensor =
+            InlineCurrentSensor::new(sim.adc(INA240A2_10MOHM_CONFIG, 0.0), INA240A2_10MOHM_CONFIG);
+        let current_controller = CurrentController::new(&sim.clock, 3.0, 300.0, 1.0);
+        let mut motor = motor.with_current_sensing(current_sensor, current_controller);
+        motor.apply_config(&config);
+        let current_controller = motor.current_controller.as_ref().unwrap();
+        assert_eq!(current_controller.pid_q.kp, 3.0);
+        assert_eq!(current_controller.pid_d.ki, 300.0);
+        assert!(motor.get_config().unwrap().current_gains.is_some());
+    }
+
+    #[test]
+    fn measure_cogging() {
+        let mut table = CoggingCompensation::new();
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = new_motor(&sim, sim.sensor(100), 7);
+        motor.apply_calibration(&CalibrationResult {
+            pole_pairs: 7,
+            is_reversed: false,
+            electrical_zero: 0.0,
+            correction: None,
+        });
+        motor.motion_profile = Some(MotionProfile::Trapezoidal(TrapezoidalProfile::new(
+            10.0, 100.0,
+        )));
+        motor.set_control_mode(ControlMode::Velocity);
+        motor.set_target(2.0);
+
+        // 8 samples per period of the cogging.
+        motor.measure_cogging(&mut table, 8 * 84, 2);
+        assert_eq!(motor.get_control_mode(), ControlMode::Velocity);
+        assert!(motor.motion_profile.is_some());
+
+        // the q voltage that holds against the modelled cogging torque at each angle.
+        let flux_linkage = sim.plant.borrow().flux_linkage();
+        let volts_per_newton_metre = 5.0 / (1.5 * 7.0 * flux_linkage);
+        let (mut fit, mut expected_squares, mut measured_squares) = (0.0, 0.0, 0.0);
+        for (i, measured) in table.get_table().iter().enumerate() {
+            let rads = i as f32 * consts::TAU / 4096.0;
+            let expected = 0.000_5 * F32(84.0 * rads).sin().0 * volts_per_newton_metre;
+            fit += expected * measured;
+            expected_squares += expected * expected;
+            measured_squares += measured * measured;
+        }
+        let correlation = fit / F32(expected_squares * measured_squares).sqrt().0;
+        let gain = fit / expected_squares;
+        assert!(correlation > 0.8, "{correlation}");
+        assert!((0.7..1.2).contains(&gain), "{gain}");
+
+        // speed ripple at a slow steady speed, without and with the table.
+        let ripple = |motor: &mut SimulatedMotor<'_>| {
+            motor.set_control_mode(ControlMode::Velocity);
+            motor.set_target(1.0);
+            for _ in 0..10_000 {
+                motor.foc_loop();
+            }
+            let mut squares = 0.0;
+            for _ in 0..10_000 {
+                motor.foc_loop();
+                let error = sim.plant.borrow().get_rads_per_s() - 1.0;
+                squares += error * error;
+            }
+            F32(squares / 10_000.0).sqrt().0
+        };
+        motor.motion_profile = None;
+        let without = ripple(&mut motor);
+        motor.set_control_mode(ControlMode::VoltageTorque);
+        motor.cogging_compensation = Some(&table);
+        let with = ripple(&mut motor);
+        assert!(with < 0.5 * without, "{with} against {without}");
+    }
+
+    #[test]
+    fn identify_parameters() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = new_motor(&sim, sim.sensor(100), 1);
+        let mut current_sensor = InlineCurrentSensor::new(
+            sim.adc(INA240A2_10MOHM_CONFIG, 0.01),
+            INA240A2_10MOHM_CONFIG,
+        );
+        // before anything flows.
+        current_sensor.calibrate_offsets(&mut motor.driver).unwrap();
+        motor.calibrate_rotary_sensor().unwrap();
+        let current_controller =
+            CurrentController::from_specification(&sim.clock, &motor.specification, 1000.0, 1.0);
+        let mut motor = motor.with_current_sensing(current_sensor, current_controller);
+        let open_loop_voltage_limit = motor.open_loop_voltage_limit;
+
+        let specification = motor.identify_parameters(1.0, 5.0).unwrap();
+        assert!((specification.phase_resistance - 5.0).abs() < 0.1);
+        assert!((specification.phase_inductance - 0.002).abs() < 0.0002);
+        assert!((specification.kv as f32 - 100.0).abs() < 5.0);
+        assert_eq!(motor.open_loop_voltage_limit, open_loop_voltage_limit);
+
+        // too much load to be dragged around, the rotor slips.
+        sim.plant.borrow_mut().set_load_torque(0.05);
+        assert_eq!(motor.identify_parameters(1.0, 5.0), None);
+        assert_eq!(motor.open_loop_voltage_limit, open_loop_voltage_limit);
+    }
+
+    type SensorlessMotor<'a> = BLDCMotor<
+        'a,
+        SimulatedDriver<'a>,
+        FluxObserver<'a, MockClock>,
+        MockClock,
+        InlineCurrentSensor<SimulatedAdc<'a>>,
+    >;
+
+    fn new_sensorless_motor(sim: &Simulation) -> SensorlessMotor<'_> {
+        let specification = BLDCMotorSpecification {
+            pole_pairs: 7,
+            kv: 100,
+            phase_resistance: 5.0,
+            phase_inductance: 0.002,
+        };
+        let mut driver = sim.driver(12.0);
+        // nothing else moves the clock, 20 kHz.
+        driver.update_period_us = 50;
+        let mut current_sensor = InlineCurrentSensor::new(
+            sim.adc(INA240A2_10MOHM_CONFIG, 0.01),
+            INA240A2_10MOHM_CONFIG,
+        );
+        current_sensor.calibrate_offsets(&mut driver).unwrap();
+        let observer = FluxObserver::new(&sim.clock, &specification, DEFAULT_FLUX_OBSERVER_CONFIG);
+        let mut motor = BLDCMotor::new(
+            specification,
+            Some(observer),
+            driver,
+            PID::new(&sim.clock, 20.0, 0.0, 0.0, 0.0),
+            PID::new(&sim.clock, 0.05, 0.5, 0.0, 0.0),
+        )
+        .with_current_sensing(
+            current_sensor,
+            CurrentController::from_specification(&sim.clock, &specification, 2000.0, 1.0),
+        );
+        motor.voltage_limit = 6.0;
+        motor
+    }
+
+    #[test]
+    fn start_sensorless() {
+        let sim = Simulation::new(GIMBAL_MOTOR);
+        let mut motor = new_sensorless_motor(&sim);
+        // the default would hold it below the handover speed.
+        motor.velocity_limit = 50.0;
+
+        assert!(motor.start_sensorless(40.0));
+        for _ in 0..20_000 {
+            motor.foc_loop();
+            // the observer finds the electrical angle itself, not just relative to where it started.
+            let observer = motor.angle.as_ref().unwrap();
+            let error = observer.get_rads() * 7.0 - sim.plant.borrow().get_electrical_angle();
+            let error = error - consts::TAU * F32(error / consts::TAU).round().0;
+            assert!(error.abs() < 0.2, "{error}");
+        }
+        let rads_per_s = sim.plant.borrow().get_rads_per_s();
+        assert!((rads_per_s - 40.0).abs() < 1.0, "{rads_per_s}");
+        let observed_rads_per_s = motor.angle.as_ref().unwrap().get_rads_per_s();
+        assert!((observed_rads_per_s - rads_per_s).abs() < 1.0);
+    }
+
+    #[test]
+    fn sensorless_start_gives_up_on_a_jammed_rotor() {
+        let mut parameters = GIMBAL_MOTOR;
+        // far more than the startup current can turn.
+        parameters.coulomb_friction = 1.0;
+        let sim = Simulation::new(parameters);
+        let mut motor = new_sensorless_motor(&sim);
+
+        assert!(!motor.start_sensorless(40.0));
+        assert_eq!(sim.plant.borrow().get_rads(), 0.0);
+        let timeout = (DEFAULT_FLUX_OBSERVER_CONFIG.startup_timeout * 1e6) as u64;
+        assert!(sim.clock.now().ticks() < timeout + 1000);
+    }
+}
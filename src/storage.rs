@@ -0,0 +1,672 @@
+// Keep what calibration and tuning found over power cycles, so the motor can start without redoing them.
+//
+// One record in flash, little endian:
+//   magic u32, version u16, flags u16, length of the payload u32,
+//   payload,
+//   crc32 of everything before it.
+// The payload is the specification, the calibration, the loop gains and, if flagged, the cogging table.
+// A record with another version is not read, change the version whenever the payload changes.
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+
+use crate::bldc_motor::{BLDCMotorSpecification, CalibrationResult};
+use crate::cogging::CoggingCompensation;
+use crate::pid::PIDGains;
+use crate::sensor::correction::{SensorCorrection, SENSOR_CORRECTION_SIZE};
+
+pub const CONFIG_VERSION: u16 = 2;
+
+// "FOCC"
+const MAGIC: u32 = 0x4343_4f46;
+const HEADER_SIZE: usize = 12;
+const FLAG_CORRECTION: u16 = 1 << 0;
+const FLAG_COGGING: u16 = 1 << 1;
+const FLAG_CURRENT_GAINS: u16 = 1 << 2;
+// specification, calibration and gains, without the cogging table.
+const FIXED_PAYLOAD_SIZE: usize = (1 + 2 + 4 + 4) + (1 + 4 + 2 * SENSOR_CORRECTION_SIZE) + 4 * 8;
+const COGGING_SIZE: usize = 4 * 4096;
+// flash is read and written this much at a time, it has to be a multiple of the flash's own sizes.
+const CHUNK_SIZE: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotorConfig {
+    pub specification: BLDCMotorSpecification,
+    // its pole pairs are always those of the specification.
+    pub calibration: CalibrationResult,
+    pub angle_gains: PIDGains,
+    pub velocity_gains: PIDGains,
+    // for both the q and the d loop, no derivative.
+    // none when there was no current loop to take them from, so a motor that has one keeps its own.
+    pub current_gains: Option<PIDGains>,
+    // whether the record holds a cogging table, when loading whether one was read.
+    // has to agree with the table given to `save`.
+    pub has_cogging_compensation: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageError {
+    // the flash gave an error, or its sizes do not fit the chunks used here.
+    Flash(NorFlashErrorKind),
+    // nothing was ever saved there, or something else was.
+    NotFound,
+    // saved by another version of the format.
+    Version(u16),
+    // the record is damaged, or was only half written.
+    Crc,
+    // the config says it has a cogging table and none was given to save, or the other way round.
+    CoggingMismatch,
+}
+
+impl<E: NorFlashError> From<E> for StorageError {
+    fn from(error: E) -> Self {
+        StorageError::Flash(error.kind())
+    }
+}
+
+// Write the config, and the cogging table if given, at offset which has to be at the start of an erase block.
+pub fn save<F: NorFlash>(
+    flash: &mut F,
+    offset: u32,
+    config: &MotorConfig,
+    cogging_compensation: Option<&CoggingCompensation>,
+) -> Result<(), StorageError> {
+    if !CHUNK_SIZE.is_multiple_of(F::WRITE_SIZE) || !(offset as usize).is_multiple_of(F::ERASE_SIZE)
+    {
+        return Err(StorageError::Flash(NorFlashErrorKind::NotAligned));
+    }
+    if config.has_cogging_compensation != cogging_compensation.is_some() {
+        return Err(StorageError::CoggingMismatch);
+    }
+
+    let mut flags = 0;
+    if config.calibration.correction.is_some() {
+        flags |= FLAG_CORRECTION;
+    }
+    if config.current_gains.is_some() {
+        flags |= FLAG_CURRENT_GAINS;
+    }
+    let mut length = FIXED_PAYLOAD_SIZE;
+    if cogging_compensation.is_some() {
+        flags |= FLAG_COGGING;
+        length += COGGING_SIZE;
+    }
+
+    // erase whole blocks, rounded up.
+    let size = HEADER_SIZE + length + 4;
+    let erase_size = size.div_ceil(F::ERASE_SIZE) * F::ERASE_SIZE;
+    flash.erase(offset, offset + erase_size as u32)?;
+
+    let mut writer = Writer::new(flash, offset);
+    writer.put(&MAGIC.to_le_bytes())?;
+    writer.put(&CONFIG_VERSION.to_le_bytes())?;
+    writer.put(&flags.to_le_bytes())?;
+    writer.put(&(length as u32).to_le_bytes())?;
+
+    let specification = &config.specification;
+    writer.put(&[specification.pole_pairs])?;
+    writer.put(&specification.kv.to_le_bytes())?;
+    writer.put_f32(specification.phase_resistance)?;
+    writer.put_f32(specification.phase_inductance)?;
+
+    let calibration = &config.calibration;
+    writer.put(&[calibration.is_reversed as u8])?;
+    writer.put_f32(calibration.electrical_zero)?;
+    let correction = calibration
+        .correction
+        .unwrap_or(SensorCorrection::from_table([0; SENSOR_CORRECTION_SIZE]));
+    for entry in correction.get_table() {
+        writer.put(&entry.to_le_bytes())?;
+    }
+
+    for gains in [&config.angle_gains, &config.velocity_gains] {
+        writer.put_f32(gains.kp)?;
+        writer.put_f32(gains.ki)?;
+        writer.put_f32(gains.kd)?;
+    }
+    // always there, so the payload is the same size either way.
+    let current_gains = config.current_gains.unwrap_or(PIDGains {
+        kp: 0.0,
+        ki: 0.0,
+        kd: 0.0,
+    });
+    writer.put_f32(current_gains.kp)?;
+    writer.put_f32(current_gains.ki)?;
+
+    if let Some(cogging_compensation) = cogging_compensation {
+        for entry in cogging_compensation.get_table() {
+            writer.put_f32(*entry)?;
+        }
+    }
+
+    let crc = writer.crc.finish();
+    writer.put(&crc.to_le_bytes())?;
+    writer.flush()
+}
+
+// Read the config back, and the cogging table into the one given if the record has one.
+// The crc is checked before anything is given back, so a damaged record leaves the table as it was.
+// Only the flash failing part way through the second read can leave the table half written.
+pub fn load<F: ReadNorFlash>(
+    flash: &mut F,
+    offset: u32,
+    cogging_compensation: Option<&mut CoggingCompensation>,
+) -> Result<MotorConfig, StorageError> {
+    if !CHUNK_SIZE.is_multiple_of(F::READ_SIZE) {
+        return Err(StorageError::Flash(NorFlashErrorKind::NotAligned));
+    }
+
+    let mut reader = Reader::new(flash, offset);
+    let magic = reader.get_u32()?;
+    if magic != MAGIC {
+        return Err(StorageError::NotFound);
+    }
+    let version = reader.get_u16()?;
+    if version != CONFIG_VERSION {
+        return Err(StorageError::Version(version));
+    }
+    let flags = reader.get_u16()?;
+    let length = reader.get_u32()? as usize;
+    let expected_length = FIXED_PAYLOAD_SIZE
+        + if flags & FLAG_COGGING != 0 {
+            COGGING_SIZE
+        } else {
+            0
+        };
+    if length != expected_length {
+        return Err(StorageError::Crc);
+    }
+
+    // check the whole record first.
+    let mut byte = [0u8];
+    for _ in 0..length {
+        reader.get(&mut byte)?;
+    }
+    let crc = reader.crc.finish();
+    if reader.get_u32()? != crc {
+        return Err(StorageError::Crc);
+    }
+
+    // then read it again, for real.
+    let mut reader = Reader::new(reader.flash, offset + HEADER_SIZE as u32);
+    let mut pole_pairs = [0u8];
+    reader.get(&mut pole_pairs)?;
+    let specification = BLDCMotorSpecification {
+        pole_pairs: pole_pairs[0],
+        kv: reader.get_u16()?,
+        phase_resistance: reader.get_f32()?,
+        phase_inductance: reader.get_f32()?,
+    };
+
+    let mut is_reversed = [0u8];
+    reader.get(&mut is_reversed)?;
+    let electrical_zero = reader.get_f32()?;
+    let mut table = [0i16; SENSOR_CORRECTION_SIZE];
+    for entry in table.iter_mut() {
+        *entry = reader.get_u16()? as i16;
+    }
+    let calibration = CalibrationResult {
+        pole_pairs: specification.pole_pairs,
+        is_reversed: is_reversed[0] != 0,
+        electrical_zero,
+        correction: if flags & FLAG_CORRECTION != 0 {
+            Some(SensorCorrection::from_table(table))
+        } else {
+            None
+        },
+    };
+
+    let mut gains = [0.0; 8];
+    for gain in gains.iter_mut() {
+        *gain = reader.get_f32()?;
+    }
+
+    let has_cogging_compensation = match cogging_compensation {
+        Some(cogging_compensation) if flags & FLAG_COGGING != 0 => {
+            for entry in cogging_compensation.get_table_mut().iter_mut() {
+                *entry = reader.get_f32()?;
+            }
+            true
+        }
+        _ => false,
+    };
+
+    Ok(MotorConfig {
+        specification,
+        calibration,
+        angle_gains: PIDGains {
+            kp: gains[0],
+            ki: gains[1],
+            kd: gains[2],
+        },
+        velocity_gains: PIDGains {
+            kp: gains[3],
+            ki: gains[4],
+            kd: gains[5],
+        },
+        current_gains: if flags & FLAG_CURRENT_GAINS != 0 {
+            Some(PIDGains {
+                kp: gains[6],
+                ki: gains[7],
+                kd: 0.0,
+            })
+        } else {
+            None
+        },
+        has_cogging_compensation,
+    })
+}
+
+// crc32 as in zip and ethernet, a bit at a time since it only runs at boot and on save.
+struct Crc32(u32);
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(0xffff_ffff)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u32;
+            for _ in 0..8 {
+                let mask = (self.0 & 1).wrapping_neg();
+                self.0 = (self.0 >> 1) ^ (0xedb8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+// Collects bytes into chunks, so the flash only sees whole aligned writes.
+struct Writer<'f, F: NorFlash> {
+    flash: &'f mut F,
+    offset: u32,
+    buffer: [u8; CHUNK_SIZE],
+    used: usize,
+    crc: Crc32,
+}
+
+impl<'f, F: NorFlash> Writer<'f, F> {
+    fn new(flash: &'f mut F, offset: u32) -> Self {
+        Writer {
+            flash,
+            offset,
+            buffer: [0xff; CHUNK_SIZE],
+            used: 0,
+            crc: Crc32::new(),
+        }
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), StorageError> {
+        self.crc.update(bytes);
+        for byte in bytes {
+            self.buffer[self.used] = *byte;
+            self.used += 1;
+            if self.used == CHUNK_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn put_f32(&mut self, value: f32) -> Result<(), StorageError> {
+        self.put(&value.to_le_bytes())
+    }
+
+    // write what is collected, padded to the write size with erased bytes.
+    fn flush(&mut self) -> Result<(), StorageError> {
+        if self.used == 0 {
+            return Ok(());
+        }
+        let length = self.used.div_ceil(F::WRITE_SIZE) * F::WRITE_SIZE;
+        self.buffer[self.used..length].fill(0xff);
+        self.flash.write(self.offset, &self.buffer[..length])?;
+        self.offset += length as u32;
+        self.buffer.fill(0xff);
+        self.used = 0;
+        Ok(())
+    }
+}
+
+// Reads the flash a chunk at a time.
+struct Reader<'f, F: ReadNorFlash> {
+    flash: &'f mut F,
+    offset: u32,
+    buffer: [u8; CHUNK_SIZE],
+    // where the next byte comes from, a full buffer needs reading.
+    next: usize,
+    crc: Crc32,
+}
+
+impl<'f, F: ReadNorFlash> Reader<'f, F> {
+    fn new(flash: &'f mut F, offset: u32) -> Self {
+        Reader {
+            flash,
+            offset,
+            buffer: [0; CHUNK_SIZE],
+            next: CHUNK_SIZE,
+            crc: Crc32::new(),
+        }
+    }
+
+    fn get(&mut self, bytes: &mut [u8]) -> Result<(), StorageError> {
+        for byte in bytes.iter_mut() {
+            if self.next == CHUNK_SIZE {
+                // the last chunk may run past the end of the flash, read what there is.
+                let available = self.flash.capacity().saturating_sub(self.offset as usize);
+                let length = CHUNK_SIZE.min(available / F::READ_SIZE * F::READ_SIZE);
+                if length == 0 {
+                    return Err(StorageError::Flash(NorFlashErrorKind::OutOfBounds));
+                }
+                self.flash.read(self.offset, &mut self.buffer[..length])?;
+                self.offset += length as u32;
+                self.next = CHUNK_SIZE - length;
+                self.buffer.copy_within(..length, self.next);
+            }
+            *byte = self.buffer[self.next];
+            self.next += 1;
+        }
+        self.crc.update(bytes);
+        Ok(())
+    }
+
+    fn get_u16(&mut self) -> Result<u16, StorageError> {
+        let mut bytes = [0u8; 2];
+        self.get(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn get_u32(&mut self) -> Result<u32, StorageError> {
+        let mut bytes = [0u8; 4];
+        self.get(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn get_f32(&mut self) -> Result<f32, StorageError> {
+        let mut bytes = [0u8; 4];
+        self.get(&mut bytes)?;
+        Ok(f32::from_le_bytes(bytes))
+    }
+}
+
+// The qspi flash the rp2040 runs its program from, SIZE bytes of it.
+// Reads go through the execute in place window. Erasing and writing turn that off,
+// so they run from ram with interrupts off, and core 1 must not be running from flash meanwhile.
+// Keep the offset clear of the program, the end of the flash is usually free.
+#[cfg(feature = "rp2040")]
+pub struct Rp2040Flash<const SIZE: usize> {
+    // the flash interface, held so nothing else can use it meanwhile.
+    _xip_ssi: rp2040_hal::pac::XIP_SSI,
+}
+
+#[cfg(feature = "rp2040")]
+impl<const SIZE: usize> Rp2040Flash<SIZE> {
+    // where the flash shows up in the address space.
+    const XIP_BASE: u32 = 0x1000_0000;
+
+    pub fn new(xip_ssi: rp2040_hal::pac::XIP_SSI) -> Self {
+        Rp2040Flash { _xip_ssi: xip_ssi }
+    }
+
+    fn check(offset: u32, length: usize, alignment: usize) -> Result<(), NorFlashErrorKind> {
+        let offset = offset as usize;
+        if !offset.is_multiple_of(alignment) || !length.is_multiple_of(alignment) {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        if offset + length > SIZE {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Ok(())
+    }
+
+    // erase or write, whichever is given, the way the pico sdk does it.
+    fn run_from_ram(offset: u32, length: usize, data: Option<&[u8]>) {
+        use rp2040_hal::rom_data;
+
+        // everything called once the flash is gone has to be looked up first.
+        let rom = RomFlashFunctions {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        };
+        // the second stage bootloader at the start of the flash puts back the fast reads, run a copy of it.
+        let mut boot2 = [0u32; 64];
+        // Safety: the bootloader is the first 256 bytes of the flash, which is mapped.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                Self::XIP_BASE as *const u32,
+                boot2.as_mut_ptr(),
+                boot2.len(),
+            );
+        }
+        let data = data.map_or(core::ptr::null(), |data| data.as_ptr());
+
+        cortex_m::interrupt::free(|_| {
+            core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+            // Safety: nothing runs from the flash until it is back, the interrupts are off
+            // and everything the function needs is in ram or the rom.
+            unsafe { flash_operation(&rom, offset, length, data, boot2.as_ptr()) };
+            core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+        });
+    }
+}
+
+#[cfg(feature = "rp2040")]
+struct RomFlashFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+// Runs from ram, it must not call anything in the flash. Erases when there is no data.
+#[cfg(feature = "rp2040")]
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn flash_operation(
+    rom: &RomFlashFunctions,
+    offset: u32,
+    length: usize,
+    data: *const u8,
+    boot2: *const u32,
+) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if data.is_null() {
+        // 64 KB block erases where they fit, with the d8h command.
+        (rom.flash_range_erase)(offset, length, 1 << 16, 0xd8);
+    } else {
+        (rom.flash_range_program)(offset, data, length);
+    }
+    (rom.flash_flush_cache)();
+    // thumb code, so the lowest bit of the address is set.
+    let boot2: unsafe extern "C" fn() = core::mem::transmute((boot2 as *const u8).add(1));
+    boot2();
+}
+
+#[cfg(feature = "rp2040")]
+impl<const SIZE: usize> embedded_storage::nor_flash::ErrorType for Rp2040Flash<SIZE> {
+    type Error = NorFlashErrorKind;
+}
+
+#[cfg(feature = "rp2040")]
+impl<const SIZE: usize> ReadNorFlash for Rp2040Flash<SIZE> {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        Self::check(offset, bytes.len(), Self::READ_SIZE)?;
+        // Safety: checked to be within the flash, which is mapped.
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                (Self::XIP_BASE + offset) as *const u8,
+                bytes.as_mut_ptr(),
+                bytes.len(),
+            );
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+#[cfg(feature = "rp2040")]
+impl<const SIZE: usize> NorFlash for Rp2040Flash<SIZE> {
+    const WRITE_SIZE: usize = 256;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        if to < from {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Self::check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+        Self::run_from_ram(from, (to - from) as usize, None);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        Self::check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        Self::run_from_ram(offset, bytes.len(), Some(bytes));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimulatedFlash;
+
+    // room for a record with a cogging table, at the second erase block.
+    type Flash = SimulatedFlash<{ 8 * 4096 }>;
+    const OFFSET: u32 = 4096;
+
+    fn config(has_cogging_compensation: bool) -> MotorConfig {
+        MotorConfig {
+            specification: BLDCMotorSpecification {
+                pole_pairs: 7,
+                kv: 100,
+                phase_resistance: 5.0,
+                phase_inductance: 0.002,
+            },
+            calibration: CalibrationResult {
+                pole_pairs: 7,
+                is_reversed: true,
+                electrical_zero: 0.455,
+                correction: Some(SensorCorrection::from_table(core::array::from_fn(|i| {
+                    i as i16 - 64
+                }))),
+            },
+            angle_gains: PIDGains {
+                kp: 20.0,
+                ki: 0.0,
+                kd: 0.1,
+            },
+            velocity_gains: PIDGains {
+                kp: 0.5,
+                ki: 10.0,
+                kd: 0.0,
+            },
+            current_gains: Some(PIDGains {
+                kp: 3.0,
+                ki: 300.0,
+                kd: 0.0,
+            }),
+            has_cogging_compensation,
+        }
+    }
+
+    fn cogging() -> CoggingCompensation {
+        let mut cogging = CoggingCompensation::new();
+        for (i, entry) in cogging.get_table_mut().iter_mut().enumerate() {
+            *entry = i as f32 * 0.001;
+        }
+        cogging
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut flash = Flash::new();
+        save(&mut flash, OFFSET, &config(false), None).unwrap();
+        // nothing before the offset is touched.
+        assert!(flash.memory[..OFFSET as usize]
+            .iter()
+            .all(|byte| *byte == 0xff));
+        assert_eq!(load(&mut flash, OFFSET, None), Ok(config(false)));
+
+        let mut saved = config(false);
+        saved.calibration.correction = None;
+        saved.current_gains = None;
+        save(&mut flash, OFFSET, &saved, None).unwrap();
+        assert_eq!(load(&mut flash, OFFSET, None), Ok(saved));
+    }
+
+    #[test]
+    fn round_trip_with_cogging() {
+        let mut flash = Flash::new();
+        let cogging = cogging();
+        save(&mut flash, OFFSET, &config(true), Some(&cogging)).unwrap();
+
+        let mut loaded = CoggingCompensation::new();
+        assert_eq!(
+            load(&mut flash, OFFSET, Some(&mut loaded)),
+            Ok(config(true))
+        );
+        assert_eq!(loaded.get_table(), cogging.get_table());
+        // without a table to read into, the rest is still read.
+        assert_eq!(load(&mut flash, OFFSET, None), Ok(config(false)));
+    }
+
+    #[test]
+    fn cogging_has_to_agree_with_the_config() {
+        let mut flash = Flash::new();
+        assert_eq!(
+            save(&mut flash, OFFSET, &config(true), None),
+            Err(StorageError::CoggingMismatch)
+        );
+        assert_eq!(
+            save(&mut flash, OFFSET, &config(false), Some(&cogging())),
+            Err(StorageError::CoggingMismatch)
+        );
+    }
+
+    #[test]
+    fn damage_is_caught() {
+        let mut flash = Flash::new();
+        assert_eq!(load(&mut flash, OFFSET, None), Err(StorageError::NotFound));
+
+        save(&mut flash, OFFSET, &config(true), Some(&cogging())).unwrap();
+        let mut loaded = CoggingCompensation::new();
+        // a bit flipped at the end of the cogging table.
+        let last = OFFSET as usize + HEADER_SIZE + FIXED_PAYLOAD_SIZE + COGGING_SIZE - 1;
+        flash.memory[last] ^= 0x01;
+        assert_eq!(
+            load(&mut flash, OFFSET, Some(&mut loaded)),
+            Err(StorageError::Crc)
+        );
+        // and the table is left as it was.
+        assert!(loaded.get_table().iter().all(|entry| *entry == 0.0));
+        flash.memory[last] ^= 0x01;
+
+        // another version.
+        flash.memory[OFFSET as usize + 4] = CONFIG_VERSION as u8 + 1;
+        assert_eq!(
+            load(&mut flash, OFFSET, None),
+            Err(StorageError::Version(CONFIG_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn offset_has_to_be_an_erase_block() {
+        let mut flash = Flash::new();
+        assert_eq!(
+            save(&mut flash, 256, &config(false), None),
+            Err(StorageError::Flash(NorFlashErrorKind::NotAligned))
+        );
+    }
+}